@@ -10,6 +10,8 @@ mod redraw_scheduler;
 mod renderer;
 mod window;
 
+#[macro_use]
+extern crate bitflags;
 #[macro_use]
 extern crate derive_new;
 #[macro_use]
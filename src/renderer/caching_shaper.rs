@@ -6,18 +6,43 @@ use font_kit::{
     properties::{Properties, Stretch, Style, Weight},
     source::SystemSource,
 };
-use log::{trace, warn};
+use log::{error, trace, warn};
 use lru::LruCache;
-use skribo::{FontCollection, FontFamily, FontRef as SkriboFont, LayoutSession, TextStyle};
-use skulpin::skia_safe::{Data, Font as SkiaFont, TextBlob, TextBlobBuilder, Typeface};
+use skribo::{
+    layout_run, FontCollection, FontFamily, FontRef as SkriboFont, Layout, LayoutSession, TextStyle,
+};
+use skulpin::skia_safe::font::Edging;
+use skulpin::skia_safe::{
+    Data, Font as SkiaFont, FontHinting, Paint, PaintStyle, Point, Rect, TextBlob, TextBlobBuilder,
+    Typeface,
+};
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::marker::PhantomData;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+
+use unicode_bidi::BidiInfo;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-use std::collections::HashMap;
-use std::iter;
+use super::font_options::{FallbackRule, FontOptions};
+use crate::editor::Style as HighlightStyle;
 
-use super::font_options::FontOptions;
+// Punctuation which commonly forms ligatures is left out, since ligature glyphs span several cells
+const STANDARD_CHARACTER_STRING: &str = concat!(
+    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890",
+    " .,;(){}[]'\"@#",
+    "\u{2500}\u{2502}"
+);
 
-const STANDARD_CHARACTER_STRING: &str =
-    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890";
+// Glyphs whose advance best represents the cell width of a monospace font
+const MONOSPACE_REFERENCE_CHARACTERS: &[char] = &['M', '0'];
+const MONOSPACE_ADVANCE_TOLERANCE: f32 = 0.01;
 
 define! {
     if #[cfg(target_os = "windows")] {
@@ -35,6 +60,21 @@ define! {
     }
 }
 
+// Private use area icons from patched Nerd Fonts, used by status lines and file trees
+const NERD_SYMBOL_FONT: &str = "Symbols Nerd Font Mono";
+// Fonts in the fallback list can be given as a file instead of a family name
+const FONT_FILE_PREFIX: &str = "file://";
+
+const TEXT_PRESENTATION_SELECTOR: char = '\u{fe0e}';
+const EMOJI_PRESENTATION_SELECTOR: char = '\u{fe0f}';
+const ZERO_WIDTH_JOINER: char = '\u{200d}';
+// Turns the digit, # or * before it into a keycap emoji
+const COMBINING_ENCLOSING_KEYCAP: char = '\u{20e3}';
+// Pairs of these are drawn as a country's flag
+const REGIONAL_INDICATORS: RangeInclusive<char> = '\u{1f1e6}'..='\u{1f1ff}';
+// Fitzpatrick modifiers change the skin tone of the emoji before them
+const SKIN_TONE_MODIFIERS: RangeInclusive<char> = '\u{1f3fb}'..='\u{1f3ff}';
+
 const EXTRA_SYMBOL_FONT: &str = "Extra Symbols.otf";
 const MISSING_GLYPH_FONT: &str = "Missing Glyphs.otf";
 
@@ -44,6 +84,244 @@ const MISSING_GLYPH_FONT: &str = "Missing Glyphs.otf";
 struct Asset;
 
 const DEFAULT_FONT_SIZE: f32 = 14.0;
+// Slant in degrees for synthesized italics when the font doesn't declare its own, negative leans
+// right like in the post table
+const DEFAULT_ITALIC_ANGLE: f32 = -14.0;
+const POST_TABLE_TAG: u32 = 0x706f_7374;
+const GSUB_TABLE_TAG: u32 = 0x4753_5542;
+const SINGLE_SUBSTITUTION_LOOKUP: u16 = 1;
+const EXTENSION_SUBSTITUTION_LOOKUP: u16 = 7;
+// COLR, CBDT and sbix, the tables holding color glyphs
+const COLOR_TABLE_TAGS: &[u32] = &[0x434f_4c52, 0x4342_4454, 0x7362_6978];
+// Tables listing the pixel sizes of bitmap strikes, CBLC next to CBDT and sbix on its own
+const CBLC_TABLE_TAG: u32 = 0x4342_4c43;
+const SBIX_TABLE_TAG: u32 = 0x7362_6978;
+const CBLC_BITMAP_SIZE_LENGTH: usize = 48;
+const CBLC_PPEM_OFFSET: usize = 44;
+// Strikes within this fraction of the requested size are drawn at their own size, since scaling
+// a bitmap by a little blurs it for hardly any change in size
+const STRIKE_SNAP_TOLERANCE: f32 = 0.15;
+const UNDERCURL_WAVELENGTH_FACTOR: f32 = 4.0;
+
+// Setting this to 1 logs at trace level which font every run came from and how the caches did
+const SHAPE_TRACE_VARIABLE: &str = "NEOVIDE_SHAPE_TRACE";
+
+lazy_static! {
+    static ref SHAPE_TRACE: bool = std::env::var(SHAPE_TRACE_VARIABLE)
+        .map(|value| value == "1")
+        .unwrap_or(false);
+}
+
+// East Asian ambiguous characters take one cell in most monospace fonts, while terminals in CJK
+// locales often give them two
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AmbiguousWidth {
+    Narrow,
+    Wide,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaperConfig {
+    // Text whose glyph advances determine the cell width when the reference characters can't
+    pub measurement_string: String,
+    pub symbol_family: String,
+    // No emoji family leaves emoji to the monochrome fallback fonts
+    pub emoji_family: Option<String>,
+    // Consults the platform's default monospace family right after the guifont families, so
+    // Latin text still has glyphs when those fonts only cover some scripts
+    pub monospace_fallback: bool,
+    // Weight for regular text, which picks the closest face of each family. Layout only knows
+    // the default instance of variable fonts, so their wght axis is left alone
+    pub weight: Option<u16>,
+    pub features: Vec<(String, u32)>,
+    // Used when neither the features nor the guifont mention kern
+    pub kerning: bool,
+    // Keeps the fractional glyph positions from layout instead of snapping glyphs to cells
+    pub subpixel: bool,
+    // Extra space in pixels added to every glyph advance and to every line, may be negative
+    pub letter_spacing: f32,
+    pub line_spacing: f32,
+    // Stroke width in pixels at the default font size used to synthesize bold
+    pub synthetic_bold_strength: f32,
+    // Number of cells between tab stops tabs get expanded to
+    pub tab_width: usize,
+    pub ambiguous_width: AmbiguousWidth,
+    // Cell widths of code points whose width from unicode-width doesn't match how the installed
+    // fonts draw them, they win over every other classification
+    pub width_overrides: HashMap<char, u8>,
+    // Stretches box drawing glyphs to exactly one cell so borders connect
+    pub box_drawing_snap: bool,
+    // Squeezes glyphs wider than their cells, like oversized symbols from fallback fonts, so they
+    // don't overlap their neighbours
+    pub clamp_wide_glyphs: bool,
+    // Draws the hexadecimal code point of characters no font has a glyph for inside a box
+    pub draw_missing_codepoint: bool,
+    // Draws control characters as their symbols from the Control Pictures block, one cell each
+    pub show_control_chars: bool,
+    // Number of graphemes laid out at once. Longer text is laid out in pieces, and lines longer
+    // than this are shaped without caching them
+    pub max_shape_len: usize,
+    // Antialiasing and hinting of every glyph, the defaults are skia's own
+    pub edging: Edging,
+    pub hinting: FontHinting,
+}
+
+// Skribo only lets harfbuzz run with its default features, so ligature features can only be
+// turned off by laying out each grapheme separately. Stylistic sets are applied after layout from
+// the font's own substitutions, other OpenType features can't be passed through.
+const LIGATURE_FEATURES: &[&str] = &["calt", "liga"];
+// Without kerning glyphs are placed by their own advances after layout, which keeps ligatures
+const KERNING_FEATURE: &str = "kern";
+
+// The last value given for a feature wins
+fn feature_enabled(features: &[(String, u32)], tag: &str) -> Option<bool> {
+    features
+        .iter()
+        .rev()
+        .find(|(feature, _)| feature == tag)
+        .map(|(_, value)| *value != 0)
+}
+
+fn warn_unsupported_features(features: &[(String, u32)]) {
+    for (feature, _) in features.iter() {
+        if feature.starts_with("ss") && stylistic_set_number(feature).is_none() {
+            warn!(
+                "Font feature {} is not a stylistic set, those go from ss01 to ss20",
+                feature
+            );
+        } else if !LIGATURE_FEATURES.contains(&feature.as_str())
+            && feature != KERNING_FEATURE
+            && stylistic_set_number(feature).is_none()
+        {
+            warn!(
+                "Font feature {} is not supported and will be ignored",
+                feature
+            );
+        }
+    }
+}
+
+fn stylistic_set_number(tag: &str) -> Option<u8> {
+    if tag.len() != 4
+        || !tag.starts_with("ss")
+        || !tag[2..].bytes().all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+    tag[2..]
+        .parse::<u8>()
+        .ok()
+        .filter(|set| (1..=20).contains(set))
+}
+
+impl ShaperConfig {
+    // Overrides, like the features of the guifont setting, win over the configured features
+    fn ligatures(&self, overrides: &[(String, u32)]) -> bool {
+        LIGATURE_FEATURES.iter().all(|tag| {
+            feature_enabled(overrides, tag)
+                .or_else(|| feature_enabled(&self.features, tag))
+                .unwrap_or(true)
+        })
+    }
+
+    fn kerning(&self, overrides: &[(String, u32)]) -> bool {
+        feature_enabled(overrides, KERNING_FEATURE)
+            .or_else(|| feature_enabled(&self.features, KERNING_FEATURE))
+            .unwrap_or(self.kerning)
+    }
+
+    // Enabled stylistic sets by number
+    fn stylistic_sets(&self, overrides: &[(String, u32)]) -> Vec<u8> {
+        let mut sets: Vec<u8> = self
+            .features
+            .iter()
+            .chain(overrides.iter())
+            .filter_map(|(feature, _)| stylistic_set_number(feature))
+            .filter(|set| {
+                let tag = format!("ss{:02}", set);
+                feature_enabled(overrides, &tag).or_else(|| feature_enabled(&self.features, &tag))
+                    == Some(true)
+            })
+            .collect();
+        sets.sort();
+        sets.dedup();
+        sets
+    }
+}
+
+impl Default for ShaperConfig {
+    fn default() -> ShaperConfig {
+        ShaperConfig {
+            measurement_string: String::from(STANDARD_CHARACTER_STRING),
+            symbol_family: String::from(NERD_SYMBOL_FONT),
+            emoji_family: Some(String::from(SYSTEM_EMOJI_FONT)),
+            monospace_fallback: true,
+            weight: None,
+            features: vec![(String::from("calt"), 1), (String::from("liga"), 1)],
+            kerning: true,
+            subpixel: false,
+            letter_spacing: 0.0,
+            line_spacing: 0.0,
+            synthetic_bold_strength: 0.5,
+            tab_width: 8,
+            ambiguous_width: AmbiguousWidth::Narrow,
+            width_overrides: HashMap::new(),
+            box_drawing_snap: true,
+            clamp_wide_glyphs: true,
+            draw_missing_codepoint: false,
+            show_control_chars: false,
+            max_shape_len: 512,
+            edging: Edging::AntiAlias,
+            hinting: FontHinting::Normal,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    pub font_capacity: usize,
+    pub blob_capacity: usize,
+    // Optional limit on the estimated memory used by cached blobs
+    pub blob_byte_budget: Option<usize>,
+}
+
+impl CacheSettings {
+    // LruCache panics when created with a capacity of zero
+    fn validated(self) -> CacheSettings {
+        if self.font_capacity == 0 || self.blob_capacity == 0 {
+            warn!(
+                "Shaper cache capacities must be non zero, clamping {:?}",
+                self
+            );
+        }
+
+        CacheSettings {
+            font_capacity: self.font_capacity.max(1),
+            blob_capacity: self.blob_capacity.max(1),
+            ..self
+        }
+    }
+}
+
+impl Default for CacheSettings {
+    fn default() -> CacheSettings {
+        CacheSettings {
+            font_capacity: 10,
+            blob_capacity: 10000,
+            blob_byte_budget: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct CacheStats {
+    pub font_hits: u64,
+    pub font_misses: u64,
+    pub blob_hits: u64,
+    pub blob_misses: u64,
+    pub cell_metrics_hits: u64,
+    pub cell_metrics_misses: u64,
+}
 
 #[derive(Clone)]
 pub struct ExtendedFontFamily {
@@ -55,25 +333,25 @@ impl ExtendedFontFamily {
         ExtendedFontFamily { fonts: Vec::new() }
     }
 
+    // Fonts skia can't draw are left out so the next family in the fallback chain is used
     pub fn add_font(&mut self, font: SkriboFont) {
-        self.fonts.push(font);
+        if skia_can_wrap(&font.font) {
+            self.fonts.push(font);
+        } else {
+            warn!(
+                "Skipping a font of {} which skia could not load",
+                font.font.family_name()
+            );
+        }
     }
 
     pub fn get(&self, props: Properties) -> Option<&Font> {
-        for handle in &self.fonts {
-            let font = &handle.font;
-            let properties = font.properties();
-
-            if properties.weight == props.weight && properties.style == props.style {
-                return Some(&font);
-            }
-        }
-
-        if let Some(handle) = &self.fonts.first() {
-            return Some(&handle.font);
-        }
-
-        None
+        let faces: Vec<Properties> = self
+            .fonts
+            .iter()
+            .map(|handle| handle.font.properties())
+            .collect();
+        closest_face(&faces, props).map(|index| &self.fonts[index].font)
     }
 
     pub fn from_normal_font_family(fonts: &[Handle]) -> ExtendedFontFamily {
@@ -99,19 +377,64 @@ impl ExtendedFontFamily {
     }
 }
 
+// Faces are matched on style first, then weight and then width. Oblique faces stand in for
+// italic ones and the other way around before falling back to an upright face
+fn face_distance(face: Properties, requested: Properties) -> (f32, f32, f32) {
+    let style_distance = match (face.style, requested.style) {
+        (face_style, requested_style) if face_style == requested_style => 0.0,
+        (Style::Normal, _) | (_, Style::Normal) => 2.0,
+        _ => 1.0,
+    };
+    (
+        style_distance,
+        (face.weight.0 - requested.weight.0).abs(),
+        (face.stretch.0 - requested.stretch.0).abs(),
+    )
+}
+
+// Families list their faces in whatever order the system returns them, so the first face isn't
+// necessarily the regular one
+fn closest_face(faces: &[Properties], requested: Properties) -> Option<usize> {
+    (0..faces.len()).min_by(|first, second| {
+        face_distance(faces[*first], requested)
+            .partial_cmp(&face_distance(faces[*second], requested))
+            .unwrap_or(Ordering::Equal)
+    })
+}
+
 pub struct FontLoader {
     cache: LruCache<String, ExtendedFontFamily>,
+    // Families the system source couldn't find, so they aren't queried again on every redraw
+    missing: HashSet<String>,
+    // A single source is created up front since constructing one re-enumerates the system fonts.
+    // SystemSource isn't Send, so the loader and the shaper owning it must stay on one thread.
     source: SystemSource,
+    #[cfg(test)]
+    source_queries: usize,
 }
 
 impl FontLoader {
     pub fn new() -> FontLoader {
         FontLoader {
             cache: LruCache::new(10),
+            missing: HashSet::new(),
             source: SystemSource::new(),
+            #[cfg(test)]
+            source_queries: 0,
         }
     }
 
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.missing.clear();
+    }
+
+    // The system source only knows the fonts installed when it was created
+    fn refresh(&mut self) {
+        self.source = SystemSource::new();
+        self.clear();
+    }
+
     fn get(&mut self, font_name: &str) -> Option<ExtendedFontFamily> {
         self.cache.get(&String::from(font_name)).cloned()
     }
@@ -138,98 +461,422 @@ impl FontLoader {
         None
     }
 
+    fn load_from_path(&mut self, font_name: &str) -> Option<ExtendedFontFamily> {
+        let path = &font_name[FONT_FILE_PREFIX.len()..];
+        match Font::from_path(path, 0) {
+            Ok(font) => {
+                let mut family = ExtendedFontFamily::new();
+                family.add_font(SkriboFont::new(font));
+                self.cache.put(String::from(font_name), family);
+                self.get(font_name)
+            }
+            Err(error) => {
+                warn!("Could not load font file {}: {:?}", path, error);
+                None
+            }
+        }
+    }
+
     fn load(&mut self, font_name: &str) -> Option<ExtendedFontFamily> {
-        let handle = match self.source.select_family_by_name(font_name) {
-            Ok(it) => it,
-            _ => return None,
-        };
+        if self.missing.contains(font_name) {
+            return None;
+        }
+
+        #[cfg(test)]
+        {
+            self.source_queries += 1;
+        }
+
+        let family = self
+            .source
+            .select_family_by_name(font_name)
+            .ok()
+            .map(|handle| ExtendedFontFamily::from_normal_font_family(handle.fonts()))
+            .filter(|family| !family.fonts.is_empty());
 
-        let family = ExtendedFontFamily::from_normal_font_family(handle.fonts());
-        if !family.fonts.is_empty() {
+        if let Some(family) = family {
             self.cache.put(String::from(font_name), family);
             self.get(font_name)
         } else {
+            self.missing.insert(String::from(font_name));
             None
         }
     }
 
+    // A family can be found without any face that loads, for example on trimmed down installs.
+    // Such a family can't stand in for anything, so it is treated like a missing one
     pub fn get_or_load(&mut self, font_name: &str) -> Option<ExtendedFontFamily> {
-        if let Some(cached) = self.get(font_name) {
+        let family = if let Some(cached) = self.get(font_name) {
             Some(cached)
+        } else if font_name.starts_with(FONT_FILE_PREFIX) {
+            self.load_from_path(font_name)
         } else if let Some(loaded) = self.load(font_name) {
             Some(loaded)
         } else {
             self.load_from_asset(font_name)
+        };
+
+        family.filter(|family| {
+            if family.fonts.is_empty() {
+                warn!("Font family {} has no usable faces, skipping it", font_name);
+                false
+            } else {
+                true
+            }
+        })
+    }
+}
+
+// f32 is neither Hash nor Eq, so float valued keys are stored by their bit pattern instead
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct OrderedF32(u32);
+
+impl OrderedF32 {
+    fn new(value: f32) -> OrderedF32 {
+        // Normalize negative zero so that it keys the same as positive zero
+        if value == 0.0 {
+            OrderedF32(0.0f32.to_bits())
+        } else {
+            OrderedF32(value.to_bits())
         }
     }
+
+    pub fn value(self) -> f32 {
+        f32::from_bits(self.0)
+    }
+}
+
+#[derive(new, Clone, Hash, PartialEq, Eq, Debug)]
+struct SkiaFontKey {
+    pub postscript_name: String,
+    pub size: OrderedF32,
+    pub synthetic_italic: bool,
+}
+
+bitflags! {
+    pub struct FontStyle: u8 {
+        const BOLD = 0b000001;
+        const ITALIC = 0b000010;
+        const UNDERLINE = 0b000100;
+        const UNDERCURL = 0b001000;
+        const STRIKETHROUGH = 0b010000;
+        const REVERSE = 0b100000;
+    }
+}
+
+impl FontStyle {
+    pub fn from_highlight(style: &HighlightStyle) -> FontStyle {
+        let mut font_style = FontStyle::empty();
+        font_style.set(FontStyle::BOLD, style.bold);
+        font_style.set(FontStyle::ITALIC, style.italic);
+        font_style.set(FontStyle::UNDERLINE, style.underline);
+        font_style.set(FontStyle::UNDERCURL, style.undercurl);
+        font_style.set(FontStyle::STRIKETHROUGH, style.strikethrough);
+        font_style.set(FontStyle::REVERSE, style.reverse);
+        font_style
+    }
+
+    // Only bold and italic pick a different face, decorations are drawn over the shaped text
+    fn face(self) -> FontStyle {
+        self & (FontStyle::BOLD | FontStyle::ITALIC)
+    }
+
+    // Shaped runs carry whether they are reversed, so reversed text is cached on its own
+    fn cache_key_style(self) -> FontStyle {
+        self.face() | (self & FontStyle::REVERSE)
+    }
+
+    fn reverse(self) -> bool {
+        self.contains(FontStyle::REVERSE)
+    }
+
+    fn bold(self) -> bool {
+        self.contains(FontStyle::BOLD)
+    }
+
+    fn italic(self) -> bool {
+        self.contains(FontStyle::ITALIC)
+    }
 }
 
 #[derive(new, Clone, Hash, PartialEq, Eq, Debug)]
-struct ShapeKey {
+pub struct ShapeKey {
     pub text: String,
-    pub bold: bool,
-    pub italic: bool,
+    pub size: OrderedF32,
+    // Runs are shaped in device pixels, so they only fit the scale factor they were shaped at
+    pub scale: OrderedF32,
+    pub style: FontStyle,
+    #[new(default)]
+    pub blend: u8,
 }
 
+fn font_properties(config: &ShaperConfig, bold: bool, italic: bool) -> Properties {
+    let weight = if bold {
+        Weight::BOLD
+    } else {
+        config
+            .weight
+            .map(|weight| Weight(weight as f32))
+            .unwrap_or(Weight::NORMAL)
+    };
+    let style = if italic { Style::Italic } else { Style::Normal };
+    Properties {
+        weight,
+        style,
+        stretch: Stretch::NORMAL,
+    }
+}
+
+// Hands back the collection along with the full names of its primary fonts and of the fallback
+// fonts after them, in the order font fallback consults them
 pub fn build_collection_by_font_name(
     loader: &mut FontLoader,
+    config: &ShaperConfig,
     fallback_list: &[String],
     bold: bool,
     italic: bool,
-) -> FontCollection {
+) -> (FontCollection, Vec<String>, Vec<String>) {
     let mut collection = FontCollection::new();
+    let mut primary_font_names = Vec::new();
+    let mut uses_default_font = fallback_list
+        .iter()
+        .any(|font_name| font_name == SYSTEM_DEFAULT_FONT);
+    let properties = font_properties(config, bold, italic);
 
-    let weight = if bold { Weight::BOLD } else { Weight::NORMAL };
-    let style = if italic { Style::Italic } else { Style::Normal };
-    let properties = Properties {
-        weight,
-        style,
-        stretch: Stretch::NORMAL,
+    let mut load_font = |font_name: &str| {
+        loader
+            .get_or_load(font_name)
+            .and_then(|family| family.get(properties).cloned())
     };
 
-    let gui_fonts = fallback_list
-        .iter()
-        .map(|fallback_item| fallback_item.as_ref())
-        .chain(iter::once(SYSTEM_DEFAULT_FONT));
+    for font_name in fallback_list {
+        if let Some(font) = load_font(font_name) {
+            primary_font_names.push(font.full_name());
+            collection.add_family(FontFamily::new_from_font(font));
+        } else {
+            warn!("Could not load font {}, skipping it", font_name);
+        }
+    }
 
-    for font_name in gui_fonts {
-        if let Some(family) = loader.get_or_load(font_name) {
-            if let Some(font) = family.get(properties) {
-                collection.add_family(FontFamily::new_from_font(font.clone()));
-                break;
+    if primary_font_names.is_empty() {
+        if let Some(font) = load_font(SYSTEM_DEFAULT_FONT) {
+            warn!(
+                "Could not load any of {}, using {} instead",
+                fallback_list.join(", "),
+                SYSTEM_DEFAULT_FONT
+            );
+            primary_font_names.push(font.full_name());
+            collection.add_family(FontFamily::new_from_font(font));
+            uses_default_font = true;
+        } else {
+            error!(
+                "Could not load any of {} or {}, using the bundled {} instead",
+                fallback_list.join(", "),
+                SYSTEM_DEFAULT_FONT,
+                EXTRA_SYMBOL_FONT
+            );
+            // The bundled fonts are always there, so the grid still gets measured from a font
+            if let Some(family) = loader.get_or_load(EXTRA_SYMBOL_FONT) {
+                primary_font_names.extend(family.fonts.iter().map(|font| font.font.full_name()));
+                collection.add_family(family.to_normal_font_family());
             }
         }
     }
 
-    for font in &[
-        SYSTEM_SYMBOL_FONT,
-        SYSTEM_EMOJI_FONT,
-        EXTRA_SYMBOL_FONT,
-        MISSING_GLYPH_FONT,
-    ] {
+    let fallback_fonts = [
+        Some(SYSTEM_DEFAULT_FONT).filter(|_| config.monospace_fallback && !uses_default_font),
+        Some(config.symbol_family.as_str()),
+        Some(SYSTEM_SYMBOL_FONT),
+        config.emoji_family.as_deref(),
+        Some(EXTRA_SYMBOL_FONT),
+        Some(MISSING_GLYPH_FONT),
+    ];
+    let mut fallback_font_names = Vec::new();
+    for font in fallback_fonts.iter().flatten() {
         if let Some(family) = loader.get_or_load(font) {
             collection.add_family(family.to_normal_font_family());
+            fallback_font_names.extend(family.fonts.iter().map(|font| font.font.full_name()));
+        } else {
+            trace!("Skipping fallback font {}", font);
         }
     }
 
-    collection
+    (collection, primary_font_names, fallback_font_names)
+}
+
+struct PinnedFont {
+    range: RangeInclusive<u32>,
+    font: SkriboFont,
+}
+
+fn load_pinned_fonts(
+    loader: &mut FontLoader,
+    config: &ShaperConfig,
+    fallback_rules: &[FallbackRule],
+    bold: bool,
+    italic: bool,
+) -> Vec<PinnedFont> {
+    let properties = font_properties(config, bold, italic);
+
+    fallback_rules
+        .iter()
+        .filter_map(|rule| {
+            let font = loader
+                .get_or_load(&rule.family)
+                .and_then(|family| family.get(properties).cloned());
+            if font.is_none() {
+                warn!("Could not load font {} for a fallback rule", rule.family);
+            }
+
+            font.map(|font| PinnedFont {
+                range: rule.range.clone(),
+                font: SkriboFont::new(font),
+            })
+        })
+        .collect()
 }
 
 struct FontSet {
     normal: FontCollection,
     bold: FontCollection,
     italic: FontCollection,
+    pinned_normal: Vec<PinnedFont>,
+    pinned_bold: Vec<PinnedFont>,
+    pinned_italic: Vec<PinnedFont>,
+    primary_font_names: HashSet<String>,
+    // Full names of every font each collection consults, primary fonts first
+    normal_chain: Vec<String>,
+    bold_chain: Vec<String>,
+    italic_chain: Vec<String>,
+    missing_glyph_font_names: HashSet<String>,
+    emoji_fonts: Vec<SkriboFont>,
+    text_symbol_fonts: Vec<SkriboFont>,
 }
 
 impl FontSet {
-    fn new(fallback_list: &[String], mut loader: &mut FontLoader) -> FontSet {
+    fn new(
+        fallback_list: &[String],
+        fallback_rules: &[FallbackRule],
+        mut loader: &mut FontLoader,
+        config: &ShaperConfig,
+    ) -> FontSet {
+        let (normal, normal_names, normal_fallbacks) =
+            build_collection_by_font_name(&mut loader, config, fallback_list, false, false);
+        let (bold, bold_names, bold_fallbacks) =
+            build_collection_by_font_name(&mut loader, config, fallback_list, true, false);
+        let (italic, italic_names, italic_fallbacks) =
+            build_collection_by_font_name(&mut loader, config, fallback_list, false, true);
+        let chain = |names: &[String], fallbacks: &[String]| {
+            names
+                .iter()
+                .chain(fallbacks)
+                .cloned()
+                .collect::<Vec<String>>()
+        };
+        let normal_chain = chain(&normal_names, &normal_fallbacks);
+        let bold_chain = chain(&bold_names, &bold_fallbacks);
+        let italic_chain = chain(&italic_names, &italic_fallbacks);
+
+        // Text that ends up in the bundled missing glyph font is drawn as boxes
+        let missing_glyph_font_names = loader
+            .get_or_load(MISSING_GLYPH_FONT)
+            .map(|family| {
+                family
+                    .fonts
+                    .iter()
+                    .map(|font| font.font.full_name())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut family_fonts = |family_name: &str| {
+            loader
+                .get_or_load(family_name)
+                .map(|family| family.fonts)
+                .unwrap_or_default()
+        };
+        let emoji_fonts = config
+            .emoji_family
+            .as_deref()
+            .map(|family_name| family_fonts(family_name))
+            .unwrap_or_default();
+        let text_symbol_fonts = [
+            config.symbol_family.as_str(),
+            SYSTEM_SYMBOL_FONT,
+            EXTRA_SYMBOL_FONT,
+        ]
+        .iter()
+        .flat_map(|family_name| family_fonts(*family_name))
+        .collect();
+
         FontSet {
-            normal: build_collection_by_font_name(&mut loader, fallback_list, false, false),
-            bold: build_collection_by_font_name(&mut loader, fallback_list, true, false),
-            italic: build_collection_by_font_name(&mut loader, fallback_list, false, true),
+            normal,
+            bold,
+            italic,
+            pinned_normal: load_pinned_fonts(loader, config, fallback_rules, false, false),
+            pinned_bold: load_pinned_fonts(loader, config, fallback_rules, true, false),
+            pinned_italic: load_pinned_fonts(loader, config, fallback_rules, false, true),
+            primary_font_names: normal_names
+                .into_iter()
+                .chain(bold_names)
+                .chain(italic_names)
+                .collect(),
+            normal_chain,
+            bold_chain,
+            italic_chain,
+            missing_glyph_font_names,
+            emoji_fonts,
+            text_symbol_fonts,
+        }
+    }
+
+    fn is_emoji_font(&self, font: &SkriboFont) -> bool {
+        let font_name = font.font.full_name();
+        self.emoji_fonts
+            .iter()
+            .any(|emoji_font| emoji_font.font.full_name() == font_name)
+    }
+
+    // Variation selectors ask for the text (U+FE0E) or emoji (U+FE0F) presentation of the
+    // character before them, which font fallback doesn't know about on its own. Emoji sequences
+    // only become a single glyph when the whole cluster is in one emoji font, otherwise they fall
+    // back to showing their parts
+    fn presentation_font(&self, collection: &FontCollection, cluster: &str) -> Option<SkriboFont> {
+        let base = cluster.chars().next()?;
+        let has_base = |font: &&SkriboFont| font_has_glyph(&font.font, base);
+
+        if cluster.contains(EMOJI_PRESENTATION_SELECTOR) {
+            self.emoji_fonts.iter().find(has_base).cloned()
+        } else if is_emoji_sequence(cluster) {
+            self.emoji_fonts
+                .iter()
+                .find(|font| {
+                    cluster.chars().all(|character| {
+                        is_invisible(character) || font_has_glyph(&font.font, character)
+                    })
+                })
+                .cloned()
+        } else if cluster.contains(TEXT_PRESENTATION_SELECTOR) {
+            let (_, font) = collection.itemize(&cluster[..base.len_utf8()]).next()?;
+            if !self.is_emoji_font(font) && has_base(&font) {
+                Some(font.clone())
+            } else {
+                self.text_symbol_fonts.iter().find(has_base).cloned()
+            }
+        } else {
+            None
         }
     }
 
+    fn is_primary(&self, font: &SkriboFont) -> bool {
+        self.primary_font_names.contains(&font.font.full_name())
+    }
+
+    fn is_missing_glyph_font(&self, font: &SkriboFont) -> bool {
+        self.missing_glyph_font_names
+            .contains(&font.font.full_name())
+    }
+
     fn get(&self, bold: bool, italic: bool) -> &FontCollection {
         match (bold, italic) {
             (true, _) => &self.bold,
@@ -237,149 +884,4173 @@ impl FontSet {
             (false, true) => &self.italic,
         }
     }
+
+    fn chain(&self, bold: bool, italic: bool) -> &[String] {
+        match (bold, italic) {
+            (true, _) => &self.bold_chain,
+            (false, false) => &self.normal_chain,
+            (false, true) => &self.italic_chain,
+        }
+    }
+
+    fn pinned(&self, bold: bool, italic: bool) -> &[PinnedFont] {
+        match (bold, italic) {
+            (true, _) => &self.pinned_bold,
+            (false, false) => &self.pinned_normal,
+            (false, true) => &self.pinned_italic,
+        }
+    }
 }
 
+// The shaper is owned by the renderer and only used from the render thread. Font collections,
+// blobs and the system font source aren't safe to share, so the marker keeps the shaper from
+// being Send and a move to another thread fails to compile instead of misbehaving at runtime.
 pub struct CachingShaper {
     pub options: FontOptions,
+    config: ShaperConfig,
     font_set: FontSet,
     font_loader: FontLoader,
-    font_cache: LruCache<String, SkiaFont>,
-    blob_cache: LruCache<ShapeKey, Vec<TextBlob>>,
+    font_cache: LruCache<SkiaFontKey, SkiaFont>,
+    blob_cache: BlobCache,
+    // Characters no loaded font had a glyph for, which are only warned about the first time
+    missing_codepoints: Vec<char>,
+    // Cell width and height per font size and scale factor for the current font set
+    cell_metrics_cache: HashMap<(OrderedF32, OrderedF32), (f32, f32)>,
+    // Vertical metrics per font size in device pixels
+    vertical_metrics_cache: HashMap<OrderedF32, VerticalMetrics>,
+    // Glyph substitutions by font full name and the stylistic sets they were read for
+    stylistic_substitutions: HashMap<(String, Vec<u8>), Rc<HashMap<u16, u16>>>,
+    // Device pixels per logical pixel of the window. Text is shaped and measured in device pixels
+    // and scaled back down when drawn, so glyphs and rows land on whole pixels
+    scale_factor: f32,
+    trace_shaping: bool,
+    stats: CacheStats,
+    // Goes up whenever fonts, sizes or features change, so shaped text kept outside the blob
+    // cache can tell it is stale
+    font_generation: u64,
+    #[cfg(test)]
+    chunks_shaped: usize,
+    _not_send: PhantomData<*const ()>,
 }
 
-fn build_skia_font_from_skribo_font(skribo_font: &SkriboFont, base_size: f32) -> Option<SkiaFont> {
-    let font_data = skribo_font.font.copy_font_data()?;
-    let skia_data = Data::new_copy(&font_data[..]);
-    let typeface = Typeface::from_data(skia_data, None)?;
+// The italic angle is a 16.16 fixed point number right after the post table's version
+fn italic_angle_from_post_table(table: &[u8]) -> Option<f32> {
+    let bytes = table.get(4..8)?;
+    let fixed = i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    Some(fixed as f32 / 65536.0)
+}
 
-    Some(SkiaFont::from_typeface(typeface, base_size))
+fn has_color_glyphs(font: &Font) -> bool {
+    COLOR_TABLE_TAGS
+        .iter()
+        .any(|tag| font.load_font_table(*tag).is_some())
 }
 
-impl CachingShaper {
-    pub fn new() -> CachingShaper {
-        let options = FontOptions::new(String::from(SYSTEM_DEFAULT_FONT), DEFAULT_FONT_SIZE);
-        let mut loader = FontLoader::new();
-        let font_set = FontSet::new(&options.fallback_list, &mut loader);
+fn read_u16(table: &[u8], offset: usize) -> Option<u16> {
+    let bytes = table.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
 
-        CachingShaper {
-            options,
-            font_set,
-            font_loader: loader,
-            font_cache: LruCache::new(10),
-            blob_cache: LruCache::new(10000),
-        }
-    }
+fn read_u32(table: &[u8], offset: usize) -> Option<u32> {
+    let bytes = table.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
 
-    fn get_skia_font(&mut self, skribo_font: &SkriboFont) -> Option<&SkiaFont> {
-        let font_name = skribo_font.font.postscript_name()?;
+// The strike count follows the version, then come the fixed size records of every strike
+fn cblc_strike_sizes(table: &[u8]) -> Vec<u16> {
+    let count = read_u32(table, 4).unwrap_or(0) as usize;
+    (0..count.min(table.len() / CBLC_BITMAP_SIZE_LENGTH))
+        .filter_map(|index| {
+            table
+                .get(8 + index * CBLC_BITMAP_SIZE_LENGTH + CBLC_PPEM_OFFSET)
+                .map(|ppem| *ppem as u16)
+        })
+        .collect()
+}
 
-        if !self.font_cache.contains(&font_name) {
-            let font = build_skia_font_from_skribo_font(skribo_font, self.options.size)?;
-            self.font_cache.put(font_name.clone(), font);
-        }
+// The strike count follows the version and flags, then the offsets of strikes starting with
+// their size
+fn sbix_strike_sizes(table: &[u8]) -> Vec<u16> {
+    let count = read_u32(table, 4).unwrap_or(0) as usize;
+    (0..count.min(table.len() / 4))
+        .filter_map(|index| {
+            let offset = read_u32(table, 8 + index * 4)? as usize;
+            read_u16(table, offset)
+        })
+        .collect()
+}
 
-        self.font_cache.get(&font_name)
+// Glyphs of a coverage table in coverage index order
+fn coverage_glyphs(table: &[u8], offset: usize) -> Option<Vec<u16>> {
+    match read_u16(table, offset)? {
+        1 => {
+            let glyph_count = read_u16(table, offset + 2)? as usize;
+            (0..glyph_count)
+                .map(|index| read_u16(table, offset + 4 + 2 * index))
+                .collect()
+        }
+        2 => {
+            let range_count = read_u16(table, offset + 2)? as usize;
+            let mut glyphs = Vec::new();
+            for index in 0..range_count {
+                let range = offset + 4 + 6 * index;
+                glyphs.extend(read_u16(table, range)?..=read_u16(table, range + 2)?);
+            }
+            Some(glyphs)
+        }
+        _ => None,
     }
+}
 
-    fn metrics(&self) -> Metrics {
-        self.font_set
-            .normal
-            .itemize("a")
-            .next()
-            .expect("Cannot get font metrics")
-            .1
-            .font
-            .metrics()
+fn add_single_substitutions(
+    table: &[u8],
+    subtable: usize,
+    substitutions: &mut HashMap<u16, u16>,
+) -> Option<()> {
+    let coverage = coverage_glyphs(table, subtable + read_u16(table, subtable + 2)? as usize)?;
+    match read_u16(table, subtable)? {
+        1 => {
+            // The delta is signed, adding it modulo 65536 is the same
+            let delta = read_u16(table, subtable + 4)?;
+            for glyph in coverage {
+                substitutions
+                    .entry(glyph)
+                    .or_insert_with(|| glyph.wrapping_add(delta));
+            }
+        }
+        2 => {
+            for (index, glyph) in coverage.into_iter().enumerate() {
+                let substitute = read_u16(table, subtable + 6 + 2 * index)?;
+                substitutions.entry(glyph).or_insert(substitute);
+            }
+        }
+        _ => return None,
     }
+    Some(())
+}
 
-    pub fn shape(&mut self, text: &str, bold: bool, italic: bool) -> Vec<TextBlob> {
-        let style = TextStyle {
-            size: self.options.size,
-        };
-
-        let session = LayoutSession::create(text, &style, &self.font_set.get(bold, italic));
-        let metrics = self.metrics();
-        let ascent = metrics.ascent * self.options.size / metrics.units_per_em as f32;
-        let mut blobs = Vec::new();
-
-        for layout_run in session.iter_all() {
-            let skribo_font = layout_run.font();
-
-            if let Some(skia_font) = self.get_skia_font(&skribo_font) {
-                let mut blob_builder = TextBlobBuilder::new();
-                let count = layout_run.glyphs().count();
-                let (glyphs, positions) =
-                    blob_builder.alloc_run_pos_h(&skia_font, count, ascent, None);
-
-                for (i, glyph) in layout_run.glyphs().enumerate() {
-                    glyphs[i] = glyph.glyph_id as u16;
-                    positions[i] = glyph.offset.x();
-                }
+fn add_stylistic_substitutions(
+    gsub: &[u8],
+    sets: &[u8],
+    substitutions: &mut HashMap<u16, u16>,
+) -> Option<()> {
+    let feature_list = read_u16(gsub, 6)? as usize;
+    let lookup_list = read_u16(gsub, 8)? as usize;
 
-                blobs.push(blob_builder.make().unwrap());
-            } else {
-                warn!("Could not load skribo font");
+    let mut lookup_indices = Vec::new();
+    for index in 0..read_u16(gsub, feature_list)? as usize {
+        let record = feature_list + 2 + 6 * index;
+        let enabled = std::str::from_utf8(gsub.get(record..record + 4)?)
+            .ok()
+            .and_then(stylistic_set_number)
+            .map_or(false, |set| sets.contains(&set));
+        if enabled {
+            let feature = feature_list + read_u16(gsub, record + 4)? as usize;
+            for lookup in 0..read_u16(gsub, feature + 2)? as usize {
+                lookup_indices.push(read_u16(gsub, feature + 4 + 2 * lookup)?);
             }
         }
+    }
+    lookup_indices.sort();
+    lookup_indices.dedup();
 
-        blobs
+    for lookup_index in lookup_indices {
+        let lookup =
+            lookup_list + read_u16(gsub, lookup_list + 2 + 2 * lookup_index as usize)? as usize;
+        let lookup_type = read_u16(gsub, lookup)?;
+        for subtable_index in 0..read_u16(gsub, lookup + 4)? as usize {
+            let mut subtable = lookup + read_u16(gsub, lookup + 6 + 2 * subtable_index)? as usize;
+            let mut subtable_type = lookup_type;
+            if lookup_type == EXTENSION_SUBSTITUTION_LOOKUP {
+                subtable_type = read_u16(gsub, subtable + 2)?;
+                subtable += read_u32(gsub, subtable + 4)? as usize;
+            }
+            if subtable_type == SINGLE_SUBSTITUTION_LOOKUP {
+                add_single_substitutions(gsub, subtable, substitutions)?;
+            }
+        }
     }
+    Some(())
+}
 
-    pub fn shape_cached(&mut self, text: &str, bold: bool, italic: bool) -> &Vec<TextBlob> {
-        let key = ShapeKey::new(text.to_string(), bold, italic);
+// Glyph substitutions of the given stylistic sets from a GSUB table, whatever the script. Stylistic
+// sets swap glyphs one for one, so other kinds of lookups are left out. The first lookup to
+// substitute a glyph wins
+fn stylistic_substitutions(gsub: &[u8], sets: &[u8]) -> HashMap<u16, u16> {
+    let mut substitutions = HashMap::new();
+    if add_stylistic_substitutions(gsub, sets, &mut substitutions).is_none() {
+        warn!("Could not read the stylistic sets of a malformed GSUB table");
+    }
+    substitutions
+}
 
-        if !self.blob_cache.contains(&key) {
-            let blobs = self.shape(text, bold, italic);
-            self.blob_cache.put(key.clone(), blobs);
+fn substitute_glyphs(glyphs: &mut [ShapedGlyph], substitutions: &HashMap<u16, u16>) {
+    for glyph in glyphs.iter_mut() {
+        if let Some(substitute) = substitutions.get(&glyph.glyph_id) {
+            glyph.glyph_id = *substitute;
         }
+    }
+}
 
-        self.blob_cache.get(&key).unwrap()
+fn bitmap_strike_sizes(font: &Font) -> Vec<u16> {
+    if let Some(table) = font.load_font_table(CBLC_TABLE_TAG) {
+        cblc_strike_sizes(&table)
+    } else if let Some(table) = font.load_font_table(SBIX_TABLE_TAG) {
+        sbix_strike_sizes(&table)
+    } else {
+        Vec::new()
     }
+}
 
-    pub fn update_font(&mut self, guifont_setting: &str) -> bool {
-        let updated = self.options.update(guifont_setting);
-        if updated {
-            trace!("Font changed: {:?}", self.options);
-            self.font_set = FontSet::new(&self.options.fallback_list, &mut self.font_loader);
-            self.font_cache.clear();
-            self.blob_cache.clear();
+fn nearest_strike(strike_sizes: &[u16], size: f32) -> Option<u16> {
+    strike_sizes.iter().cloned().min_by(|first, second| {
+        (*first as f32 - size)
+            .abs()
+            .partial_cmp(&(*second as f32 - size).abs())
+            .unwrap_or(Ordering::Equal)
+    })
+}
+
+// Bitmap fonts are drawn at their nearest strike when it's close enough, outline fonts always
+// at the requested size
+fn bitmap_font_size(strike_sizes: &[u16], size: f32) -> f32 {
+    match nearest_strike(strike_sizes, size) {
+        Some(strike) if (strike as f32 - size).abs() <= size * STRIKE_SNAP_TOLERANCE => {
+            strike as f32
         }
-        updated
+        _ => size,
     }
+}
 
-    pub fn font_base_dimensions(&mut self) -> (f32, f32) {
-        let metrics = self.metrics();
-        let font_height =
-            (metrics.ascent - metrics.descent) * self.options.size / metrics.units_per_em as f32;
-        let style = TextStyle {
-            size: self.options.size,
-        };
-        let session =
-            LayoutSession::create(STANDARD_CHARACTER_STRING, &style, &self.font_set.normal);
-        let layout_run = session.iter_all().next().unwrap();
-        let glyph_offsets: Vec<f32> = layout_run.glyphs().map(|glyph| glyph.offset.x()).collect();
-        let glyph_advances: Vec<f32> = glyph_offsets
-            .windows(2)
-            .map(|pair| pair[1] - pair[0])
-            .collect();
+// Skia skews around the baseline, so glyphs keep their horizontal position there and only lean
+// within their cells above it
+fn synthetic_italic_skew(font: &Font) -> f32 {
+    let italic_angle = font
+        .load_font_table(POST_TABLE_TAG)
+        .and_then(|table| italic_angle_from_post_table(&table))
+        .filter(|angle| *angle != 0.0)
+        .unwrap_or(DEFAULT_ITALIC_ANGLE);
+    italic_angle.to_radians().tan()
+}
 
-        let mut amounts = HashMap::new();
+// Real styled faces are picked when building the font set, so only the styles the selected face
+// is missing get synthesized
+fn needs_synthetic_bold(font: &Font, bold: bool) -> bool {
+    bold && font.properties().weight < Weight::SEMIBOLD
+}
 
-        for advance in glyph_advances.iter() {
-            amounts
-                .entry(advance.to_string())
-                .and_modify(|e| *e += 1)
-                .or_insert(1);
-        }
+fn needs_synthetic_italic(font: &Font, italic: bool) -> bool {
+    italic && font.properties().style == Style::Normal
+}
 
-        let (font_width, _) = amounts.into_iter().max_by_key(|(_, count)| *count).unwrap();
-        let font_width = font_width.parse::<f32>().unwrap();
+// The strength is given for the default font size and grows with the text so small text
+// doesn't smear
+fn synthetic_bold_stroke_width(strength: f32, size: f32) -> f32 {
+    strength * size / DEFAULT_FONT_SIZE
+}
+
+// Skia rejects some fonts font_kit loads fine, such as bitmap only or malformed ones
+fn skia_typeface(font_data: &[u8]) -> Option<Typeface> {
+    Typeface::from_data(Data::new_copy(font_data), None)
+}
+
+fn skia_can_wrap(font: &Font) -> bool {
+    font.copy_font_data()
+        .and_then(|font_data| skia_typeface(&font_data))
+        .is_some()
+}
+
+fn build_skia_font_from_skribo_font(
+    skribo_font: &SkriboFont,
+    font_key: &SkiaFontKey,
+    config: &ShaperConfig,
+) -> Option<SkiaFont> {
+    let font_data = skribo_font.font.copy_font_data()?;
+    let typeface = skia_typeface(&font_data)?;
 
-        (font_width, font_height)
+    let size = bitmap_font_size(
+        &bitmap_strike_sizes(&skribo_font.font),
+        font_key.size.value(),
+    );
+    let mut skia_font = SkiaFont::from_typeface(typeface, size);
+    skia_font.set_edging(config.edging);
+    skia_font.set_hinting(config.hinting);
+    if font_key.synthetic_italic {
+        skia_font.set_skew_x(synthetic_italic_skew(&skribo_font.font));
     }
 
-    pub fn underline_position(&mut self) -> f32 {
-        let metrics = self.metrics();
-        -metrics.underline_position * self.options.size / metrics.units_per_em as f32
+    Some(skia_font)
+}
+
+#[derive(new, Clone, Copy, PartialEq, Debug)]
+struct ShapedGlyph {
+    pub glyph_id: u16,
+    pub position: Point,
+}
+
+struct FontRun {
+    font: SkriboFont,
+    // Whether the font is one of the primary fonts of the font set the run was laid out with
+    primary: bool,
+    glyphs: Vec<ShapedGlyph>,
+    advance: f32,
+    cell_width: usize,
+    scale_x: f32,
+    missing: Vec<char>,
+}
+
+// Vertical positions are relative to the top of the cell
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DecorationMetrics {
+    pub underline_y: f32,
+    pub underline_thickness: f32,
+    pub undercurl_wavelength: f32,
+    pub strikethrough_y: f32,
+}
+
+// Cell metrics with the row height snapped to whole device pixels, and the baseline every run is
+// drawn on measured from the top of the row
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SnappedCellMetrics {
+    pub font_width: f32,
+    pub snapped_row_height: f32,
+    pub baseline: f32,
+}
+
+// Distances from the baseline in pixels, with the descent below it being negative like in
+// font-kit. Glyphs are drawn at the ascent from the top of the cell
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct VerticalMetrics {
+    pub ascent: f32,
+    pub descent: f32,
+}
+
+#[derive(Clone)]
+pub struct ShapedRun {
+    pub font_name: String,
+    // Runs of whitespace have nothing to draw and come without a blob
+    pub blob: Option<TextBlob>,
+    pub advance: f32,
+    pub cell_width: usize,
+    // Width of the run's cells, which glyphs bleeding past their cells can be clipped to
+    pub clip_width: f32,
+    pub used_fallback: bool,
+    pub has_missing_glyphs: bool,
+    pub glyph_count: usize,
+    pub decorations: DecorationMetrics,
+    // Stroke width used to thicken the glyphs when bold had to be synthesized
+    pub synthetic_bold_stroke: Option<f32>,
+    // Transparency from the highlight's blend attribute, 0 is opaque and 100 fully transparent
+    pub blend: u8,
+    // Outlines drawn around missing code points, relative to the run like the blob
+    pub codepoint_boxes: Vec<Rect>,
+    // Glyphs come with their own colors, so the paint's color doesn't apply to them
+    pub is_color: bool,
+    // Drawn with the highlight's foreground and background swapped
+    pub reverse: bool,
+    // Distance from the top of the row to the baseline the blob is drawn at, snapped to a whole
+    // device pixel. Runs from fallback fonts are drawn on the primary font's baseline whatever
+    // their own ascent, so they line up with the text around them
+    pub baseline: f32,
+}
+
+impl ShapedRun {
+    // Synthesized bold is drawn by stroking the glyph outlines on top of filling them. Color
+    // glyphs are images without outlines, so they are only ever filled
+    pub fn apply_paint_style(&self, paint: &mut Paint) {
+        match self.synthetic_bold_stroke {
+            Some(stroke_width) if !self.is_color => {
+                paint.set_style(PaintStyle::StrokeAndFill);
+                paint.set_stroke_width(stroke_width);
+            }
+            _ => {
+                paint.set_style(PaintStyle::Fill);
+            }
+        }
+    }
+
+    // Fades the paint's alpha by the run's blend
+    pub fn blended_alpha(&self, alpha: u8) -> u8 {
+        let opacity = 100 - u32::from(self.blend.min(100));
+        (u32::from(alpha) * opacity / 100) as u8
+    }
+}
+
+// Runs of a piece of a line, positioned relative to the cell the piece starts at
+#[derive(Clone)]
+pub struct LineChunk {
+    pub text: String,
+    pub style: FontStyle,
+    pub blend: u8,
+    // The shaper's font generation the runs were shaped with
+    pub font_generation: u64,
+    pub cell_offset: usize,
+    pub runs: Vec<ShapedRun>,
+}
+
+// Cell size of the primary font saved between sessions, so the first frame doesn't have to lay
+// out the measurement string. Files from another font, spacing or version of Neovide are ignored
+const METRICS_CACHE_MAGIC: &[u8] = b"NVMC";
+
+#[derive(Clone, PartialEq, Debug)]
+struct MetricsCache {
+    version: String,
+    font_name: String,
+    measurement_string: String,
+    size: f32,
+    scale_factor: f32,
+    letter_spacing: f32,
+    line_spacing: f32,
+    cell_metrics: (f32, f32),
+}
+
+fn push_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend(&(string.len() as u32).to_le_bytes());
+    bytes.extend(string.as_bytes());
+}
+
+fn read_le_u32(bytes: &[u8], offset: &mut usize) -> Option<u32> {
+    let value = bytes.get(*offset..*offset + 4)?;
+    *offset += 4;
+    Some(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+fn read_le_f32(bytes: &[u8], offset: &mut usize) -> Option<f32> {
+    read_le_u32(bytes, offset).map(f32::from_bits)
+}
+
+fn read_string(bytes: &[u8], offset: &mut usize) -> Option<String> {
+    let length = read_le_u32(bytes, offset)? as usize;
+    let string = bytes.get(*offset..*offset + length)?;
+    *offset += length;
+    String::from_utf8(string.to_vec()).ok()
+}
+
+impl MetricsCache {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = METRICS_CACHE_MAGIC.to_vec();
+        push_string(&mut bytes, &self.version);
+        push_string(&mut bytes, &self.font_name);
+        push_string(&mut bytes, &self.measurement_string);
+        for value in [
+            self.size,
+            self.scale_factor,
+            self.letter_spacing,
+            self.line_spacing,
+            self.cell_metrics.0,
+            self.cell_metrics.1,
+        ]
+        .iter()
+        {
+            bytes.extend(&value.to_bits().to_le_bytes());
+        }
+        bytes
+    }
+
+    fn deserialize(bytes: &[u8]) -> Option<MetricsCache> {
+        if !bytes.starts_with(METRICS_CACHE_MAGIC) {
+            return None;
+        }
+
+        let mut offset = METRICS_CACHE_MAGIC.len();
+        let version = read_string(bytes, &mut offset)?;
+        let font_name = read_string(bytes, &mut offset)?;
+        let measurement_string = read_string(bytes, &mut offset)?;
+        let size = read_le_f32(bytes, &mut offset)?;
+        let scale_factor = read_le_f32(bytes, &mut offset)?;
+        let letter_spacing = read_le_f32(bytes, &mut offset)?;
+        let line_spacing = read_le_f32(bytes, &mut offset)?;
+        let cell_metrics = (
+            read_le_f32(bytes, &mut offset)?,
+            read_le_f32(bytes, &mut offset)?,
+        );
+
+        Some(MetricsCache {
+            version,
+            font_name,
+            measurement_string,
+            size,
+            scale_factor,
+            letter_spacing,
+            line_spacing,
+            cell_metrics,
+        })
+    }
+}
+
+// Rough sizes of a blob and of each glyph in it, used to keep the blob cache within its budget
+const ESTIMATED_RUN_BYTES: usize = 128;
+const ESTIMATED_GLYPH_BYTES: usize = 10;
+
+fn estimated_bytes(key: &ShapeKey, runs: &[ShapedRun]) -> usize {
+    let run_bytes: usize = runs
+        .iter()
+        .map(|run| ESTIMATED_RUN_BYTES + run.glyph_count * ESTIMATED_GLYPH_BYTES)
+        .sum();
+    key.text.len() + run_bytes
+}
+
+// Least recently used blobs are evicted once the cache holds too many entries, or when a byte
+// budget is set, once their estimated size goes over it. The newest entry is always kept so that
+// it can be handed out right after being inserted. Runs are shared with the callers, so they stay
+// usable while the shaper keeps shaping
+struct BlobCache {
+    entries: LruCache<ShapeKey, Rc<Vec<ShapedRun>>>,
+    byte_budget: Option<usize>,
+    bytes: usize,
+}
+
+impl BlobCache {
+    fn new(capacity: usize, byte_budget: Option<usize>) -> BlobCache {
+        BlobCache {
+            entries: LruCache::new(capacity),
+            byte_budget,
+            bytes: 0,
+        }
+    }
+
+    fn contains(&self, key: &ShapeKey) -> bool {
+        self.entries.contains(key)
+    }
+
+    fn get(&mut self, key: &ShapeKey) -> Option<Rc<Vec<ShapedRun>>> {
+        self.entries.get(key).cloned()
+    }
+
+    fn put(&mut self, key: ShapeKey, runs: Vec<ShapedRun>) {
+        if !self.pop(&key) && self.entries.len() == self.entries.cap() {
+            self.pop_lru();
+        }
+
+        self.bytes += estimated_bytes(&key, &runs);
+        self.entries.put(key, Rc::new(runs));
+
+        if let Some(byte_budget) = self.byte_budget {
+            while self.bytes > byte_budget && self.entries.len() > 1 {
+                self.pop_lru();
+            }
+        }
+    }
+
+    fn pop(&mut self, key: &ShapeKey) -> bool {
+        match self.entries.pop(key) {
+            Some(runs) => {
+                self.bytes -= estimated_bytes(key, &runs);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_lru(&mut self) {
+        if let Some((key, runs)) = self.entries.pop_lru() {
+            self.bytes -= estimated_bytes(&key, &runs);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+fn decoration_metrics(skia_font: &SkiaFont, baseline: f32) -> DecorationMetrics {
+    let (_, metrics) = skia_font.metrics();
+
+    // Fonts aren't required to provide decoration metrics, so guess from the font size otherwise
+    let underline_thickness = metrics
+        .underline_thickness()
+        .unwrap_or_else(|| skia_font.size() / 10.0);
+    let underline_position = metrics
+        .underline_position()
+        .unwrap_or(metrics.descent / 2.0);
+    let strikeout_position = metrics
+        .strikeout_position()
+        .unwrap_or(-metrics.x_height / 2.0);
+
+    DecorationMetrics {
+        underline_y: baseline + underline_position,
+        underline_thickness,
+        undercurl_wavelength: underline_thickness * UNDERCURL_WAVELENGTH_FACTOR,
+        strikethrough_y: baseline + strikeout_position,
+    }
+}
+
+// Glyphs are positioned relative to the baseline the blob is drawn at. Skia can't build a blob
+// without glyphs
+fn make_blob(skia_font: &SkiaFont, glyphs: &[ShapedGlyph]) -> Option<TextBlob> {
+    if glyphs.is_empty() {
+        return None;
+    }
+
+    let mut blob_builder = TextBlobBuilder::new();
+    let (glyph_ids, positions) = blob_builder.alloc_run_pos(skia_font, glyphs.len(), None);
+
+    for (i, glyph) in glyphs.iter().enumerate() {
+        glyph_ids[i] = glyph.glyph_id;
+        positions[i] = glyph.position;
+    }
+
+    blob_builder.make()
+}
+
+fn font_has_glyph(font: &Font, character: char) -> bool {
+    font.glyph_for_char(character)
+        .filter(|glyph_id| *glyph_id != 0)
+        .is_some()
+}
+
+// Characters which fonts often don't have glyphs for, but which don't draw anything either
+fn is_emoji_sequence(cluster: &str) -> bool {
+    let mut characters = cluster.chars();
+    match (characters.next(), characters.next()) {
+        (Some(first), Some(second)) => {
+            cluster.contains(ZERO_WIDTH_JOINER)
+                || ((first.is_ascii_digit() || first == '#' || first == '*')
+                    && cluster.ends_with(COMBINING_ENCLOSING_KEYCAP))
+                || cluster
+                    .chars()
+                    .any(|character| SKIN_TONE_MODIFIERS.contains(&character))
+                || (REGIONAL_INDICATORS.contains(&first) && REGIONAL_INDICATORS.contains(&second))
+        }
+        _ => false,
+    }
+}
+
+// Standard and ideographic variation selectors pick a variant of the glyph before them. Fonts
+// without that variant draw the base glyph and nothing for the selector
+fn is_variation_selector(character: char) -> bool {
+    ('\u{fe00}'..='\u{fe0f}').contains(&character)
+        || ('\u{e0100}'..='\u{e01ef}').contains(&character)
+}
+
+fn is_invisible(character: char) -> bool {
+    character.is_whitespace()
+        || character.is_control()
+        || ('\u{200b}'..='\u{200f}').contains(&character)
+        || is_variation_selector(character)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum LayoutSegment<'a> {
+    Text(&'a str),
+    Cluster(&'a str),
+    BoxDrawing(&'a str),
+    // A whole right to left bidi run, laid out in one piece so its letters can join
+    RightToLeft(&'a str),
+}
+
+// Splits text into pieces which are laid out independently. Grapheme clusters made of several
+// code points always get their own piece so they can be kept in a single font, and when ligatures
+// are disabled every grapheme is laid out separately.
+fn layout_segments(text: &str, ligatures: bool) -> Vec<LayoutSegment> {
+    let mut segments = Vec::new();
+    let mut text_start = None;
+
+    for (index, grapheme) in text.grapheme_indices(true) {
+        let single_code_point = grapheme.chars().nth(1).is_none();
+
+        if single_code_point && ligatures {
+            text_start.get_or_insert(index);
+            continue;
+        }
+
+        if let Some(start) = text_start.take() {
+            segments.push(LayoutSegment::Text(&text[start..index]));
+        }
+
+        if single_code_point {
+            segments.push(LayoutSegment::Text(grapheme));
+        } else {
+            segments.push(LayoutSegment::Cluster(grapheme));
+        }
+    }
+
+    if let Some(start) = text_start {
+        segments.push(LayoutSegment::Text(&text[start..]));
+    }
+
+    segments
+}
+
+// Splits a line into runs in visual order using the unicode bidi algorithm, along with whether
+// each run goes right to left
+fn bidi_runs(text: &str) -> Vec<(&str, bool)> {
+    let bidi_info = BidiInfo::new(text, None);
+    if !bidi_info.has_rtl() {
+        return vec![(text, false)];
+    }
+
+    let mut runs = Vec::new();
+    for paragraph in bidi_info.paragraphs.iter() {
+        let (levels, level_runs) = bidi_info.visual_runs(paragraph, paragraph.range.clone());
+        for range in level_runs {
+            runs.push((&text[range.clone()], levels[range.start].is_rtl()));
+        }
+    }
+    runs
+}
+
+// Like layout_segments, but in visual order. Right to left runs stay whole and are mirrored after
+// layout, so every grapheme still lands in its own cells.
+fn visual_segments(text: &str, ligatures: bool) -> Vec<LayoutSegment> {
+    let mut segments = Vec::new();
+
+    for (run_text, rtl) in bidi_runs(text) {
+        if rtl {
+            segments.push(LayoutSegment::RightToLeft(run_text));
+        } else {
+            segments.extend(layout_segments(run_text, ligatures));
+        }
+    }
+
+    segments
+}
+
+// Splits long text segments into bounded chunks, so no single layout pass grows with the length
+// of the line
+fn bounded_segments(segment: LayoutSegment, max_len: usize) -> Vec<LayoutSegment> {
+    match segment {
+        LayoutSegment::Text(text) => bounded_chunks(text, max_len)
+            .into_iter()
+            .map(LayoutSegment::Text)
+            .collect(),
+        _ => vec![segment],
+    }
+}
+
+// Horizontal glyph positions and the advance of a layout. Without kerning they are summed up from
+// the font's advance of every glyph, dropping the adjustments between pairs while the glyphs
+// harfbuzz substituted, like ligatures, stay
+fn glyph_positions(layout: &Layout, font: &Font, size: f32, kerning: bool) -> (Vec<f32>, f32) {
+    if kerning {
+        let positions = layout.glyphs.iter().map(|glyph| glyph.offset.x()).collect();
+        return (positions, layout.advance.x());
+    }
+
+    let scale = size / font.metrics().units_per_em as f32;
+    let mut positions = Vec::with_capacity(layout.glyphs.len());
+    let mut x = 0.0;
+    for glyph in layout.glyphs.iter() {
+        positions.push(x);
+        x += font
+            .advance(glyph.glyph_id)
+            .map(|advance| advance.x() * scale)
+            .unwrap_or(0.0);
+    }
+    (positions, x)
+}
+
+// Skribo lays text out left to right, so right to left text is flipped around within its advance.
+// Marks keep their offset from the glyph they are attached to, which is the last one before them
+// with an advance of its own
+fn mirrored_positions(
+    layout: &Layout,
+    font: &Font,
+    size: f32,
+    positions: &[f32],
+    advance: f32,
+) -> Vec<f32> {
+    let scale = size / font.metrics().units_per_em as f32;
+    let mut mirrored = Vec::with_capacity(positions.len());
+    let mut base = None;
+    for (glyph, position) in layout.glyphs.iter().zip(positions.iter()) {
+        let glyph_advance = font
+            .advance(glyph.glyph_id)
+            .map(|glyph_advance| glyph_advance.x() * scale)
+            .unwrap_or(0.0);
+        let mirrored_position = match base {
+            Some((base_position, mirrored_base)) if glyph_advance <= 0.0 => {
+                mirrored_base + position - base_position
+            }
+            _ => {
+                let mirrored_base = advance - position - glyph_advance;
+                base = Some((*position, mirrored_base));
+                mirrored_base
+            }
+        };
+        mirrored.push(mirrored_position);
+    }
+    mirrored
+}
+
+fn is_box_drawing(character: char) -> bool {
+    ('\u{2500}'..='\u{257f}').contains(&character)
+}
+
+// Moves box drawing characters out of text segments so they can be stretched to the cell size
+// without affecting the text around them
+fn split_box_drawing(segment: LayoutSegment) -> Vec<LayoutSegment> {
+    let text = match segment {
+        LayoutSegment::Text(text) => text,
+        _ => return vec![segment],
+    };
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut in_box_drawing = false;
+
+    for (index, character) in text.char_indices() {
+        if is_box_drawing(character) != in_box_drawing {
+            if index > start {
+                segments.push(if in_box_drawing {
+                    LayoutSegment::BoxDrawing(&text[start..index])
+                } else {
+                    LayoutSegment::Text(&text[start..index])
+                });
+            }
+            start = index;
+            in_box_drawing = !in_box_drawing;
+        }
+    }
+
+    if start < text.len() {
+        segments.push(if in_box_drawing {
+            LayoutSegment::BoxDrawing(&text[start..])
+        } else {
+            LayoutSegment::Text(&text[start..])
+        });
+    }
+
+    segments
+}
+
+// Splits a segment into the pieces of text each font is responsible for. Clusters are kept in the
+// font chosen for their base character if that font covers the whole cluster, so that combining
+// marks are positioned against their base glyph. Variation selectors always stay with their base
+// character, so the font can pick the variant instead of the selector landing in another font.
+fn itemize_segment<'a>(
+    collection: &FontCollection,
+    segment: LayoutSegment<'a>,
+) -> Vec<(&'a str, SkriboFont)> {
+    let text = match segment {
+        LayoutSegment::Text(text)
+        | LayoutSegment::BoxDrawing(text)
+        | LayoutSegment::RightToLeft(text) => text,
+        LayoutSegment::Cluster(cluster) => {
+            if let Some((_, base_font)) = collection.itemize(cluster).next() {
+                if cluster.chars().all(|character| {
+                    is_variation_selector(character) || font_has_glyph(&base_font.font, character)
+                }) {
+                    return vec![(cluster, base_font.clone())];
+                }
+            }
+            cluster
+        }
+    };
+
+    collection
+        .itemize(text)
+        .map(|(range, font)| (&text[range], font.clone()))
+        .collect()
+}
+
+// Moves graphemes covered by a fallback rule into the rule's font, as long as that font has
+// glyphs for the whole grapheme
+fn apply_fallback_rules<'a>(
+    items: Vec<(&'a str, SkriboFont)>,
+    pinned: &[PinnedFont],
+) -> Vec<(&'a str, SkriboFont)> {
+    if pinned.is_empty() {
+        return items;
+    }
+
+    let pinned_index = |grapheme: &str| {
+        let first = grapheme.chars().next()? as u32;
+        pinned.iter().position(|pinned_font| {
+            pinned_font.range.contains(&first)
+                && grapheme
+                    .chars()
+                    .all(|character| font_has_glyph(&pinned_font.font.font, character))
+        })
+    };
+
+    let mut result = Vec::new();
+    for (text, font) in items {
+        let font_for = |index: Option<usize>| match index {
+            Some(index) => pinned[index].font.clone(),
+            None => font.clone(),
+        };
+
+        let mut start = 0;
+        let mut current = None;
+        for (index, grapheme) in text.grapheme_indices(true) {
+            let grapheme_pin = pinned_index(grapheme);
+            if index > start && grapheme_pin != current {
+                result.push((&text[start..index], font_for(current)));
+                start = index;
+            }
+            current = grapheme_pin;
+        }
+
+        if start < text.len() {
+            result.push((&text[start..], font_for(current)));
+        }
+    }
+
+    result
+}
+
+// Horizontal scale which fits glyphs advancing further than their cells back into them. Glyphs
+// which fit are left alone
+fn clamped_scale_x(native_advance: f32, cell_count: usize, cell_advance: f32) -> f32 {
+    let cells_advance = cell_count.max(1) as f32 * cell_advance;
+    if native_advance > cells_advance + MONOSPACE_ADVANCE_TOLERANCE {
+        cells_advance / native_advance
+    } else {
+        1.0
+    }
+}
+
+// Finds the cell boundary nearest to a glyph offset while keeping it inside the cells its text
+// covers, so wide glyphs and ligatures from fonts with other advances still line up
+fn nearest_cell(offset: f32, font_width: f32, cell_count: usize) -> usize {
+    let last_cell = cell_count.saturating_sub(1) as f32;
+    (offset / font_width).round().max(0.0).min(last_cell) as usize
+}
+
+// Overrides apply to the grapheme's base character, so they also cover it with variation
+// selectors or modifiers
+fn grapheme_cell_width(grapheme: &str, config: &ShaperConfig) -> usize {
+    let overridden_width = grapheme
+        .chars()
+        .next()
+        .and_then(|character| config.width_overrides.get(&character));
+    if let Some(width) = overridden_width {
+        return (*width as usize).min(2);
+    }
+
+    let width = match config.ambiguous_width {
+        AmbiguousWidth::Narrow => grapheme.width(),
+        AmbiguousWidth::Wide => grapheme.width_cjk(),
+    };
+    // Multi code point emoji sequences sum the widths of their parts, but still only take up two
+    // cells
+    width.min(2)
+}
+
+// Spaces which only differ from U+0020 in line breaking or in their width in proportional text.
+// In the grid they take up their cells like any other space
+fn is_no_break_space(character: char) -> bool {
+    matches!(character, '\u{a0}' | '\u{2007}' | '\u{202f}')
+}
+
+fn is_blank(character: char) -> bool {
+    character == ' ' || character == '\t' || character == '\u{3000}' || is_no_break_space(character)
+}
+
+// Many fonts have no glyph for the no-break spaces or give them another advance, which would
+// pull them from a fallback font or shift the text after them
+fn no_break_spaces_as_spaces(text: &str) -> Cow<str> {
+    if text.chars().any(is_no_break_space) {
+        Cow::Owned(
+            text.chars()
+                .map(|character| {
+                    if is_no_break_space(character) {
+                        ' '
+                    } else {
+                        character
+                    }
+                })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+// The C0 controls and DEL have symbols in the same order from U+2400 on. Tabs are expanded before
+// this and stay blank
+fn control_picture(character: char) -> char {
+    match character {
+        '\u{0}'..='\u{1f}' => std::char::from_u32(0x2400 + character as u32).unwrap_or(character),
+        '\u{7f}' => '\u{2421}',
+        _ => character,
+    }
+}
+
+fn control_pictures<'a>(text: &'a str, config: &ShaperConfig) -> Cow<'a, str> {
+    if config.show_control_chars
+        && text
+            .chars()
+            .any(|character| control_picture(character) != character)
+    {
+        Cow::Owned(text.chars().map(control_picture).collect())
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+// Tab stops are counted in cells, so that wide characters before a tab still line up
+fn expand_tabs<'a>(text: &'a str, config: &ShaperConfig) -> Cow<'a, str> {
+    if !text.contains('\t') {
+        return Cow::Borrowed(text);
+    }
+
+    let tab_width = config.tab_width.max(1);
+    let mut expanded = String::with_capacity(text.len());
+    let mut column = 0;
+    for grapheme in text.graphemes(true) {
+        if grapheme == "\t" {
+            let spaces = tab_width - column % tab_width;
+            expanded.extend(std::iter::repeat(' ').take(spaces));
+            column += spaces;
+        } else {
+            expanded.push_str(grapheme);
+            column += grapheme_cell_width(grapheme, config);
+        }
+    }
+    Cow::Owned(expanded)
+}
+
+// Chunks end after a run of spaces. Ligatures never reach across spaces, so every chunk shapes the
+// same on its own as within the line. Tab stops and right to left text depend on the whole line,
+// so such lines are kept in one piece
+fn line_chunks(line: &str) -> Vec<&str> {
+    if line.is_empty() {
+        return Vec::new();
+    }
+    if line.contains('\t') || BidiInfo::new(line, None).has_rtl() {
+        return vec![line];
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut after_space = false;
+    for (index, grapheme) in line.grapheme_indices(true) {
+        let space = grapheme == " ";
+        if after_space && !space {
+            chunks.push(&line[start..index]);
+            start = index;
+        }
+        after_space = space;
+    }
+    chunks.push(&line[start..]);
+    chunks
+}
+
+// Pieces of at most max_len graphemes. They end after a run of spaces where there is one, like
+// line chunks, and between two graphemes otherwise
+fn bounded_chunks(line: &str, max_len: usize) -> Vec<&str> {
+    let max_len = max_len.max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut length = 0;
+    let mut last_break = None;
+    let mut after_space = false;
+    for (index, grapheme) in line.grapheme_indices(true) {
+        let space = grapheme == " ";
+        if after_space && !space {
+            last_break = Some((index, length));
+        }
+        if length == max_len {
+            let (end, end_length) = last_break
+                .filter(|(end, _)| *end > start)
+                .unwrap_or((index, length));
+            chunks.push(&line[start..end]);
+            start = end;
+            length -= end_length;
+            last_break = None;
+        }
+        after_space = space;
+        length += 1;
+    }
+    if start < line.len() {
+        chunks.push(&line[start..]);
+    }
+    chunks
+}
+
+fn cell_width(text: &str, config: &ShaperConfig) -> usize {
+    text.graphemes(true)
+        .map(|grapheme| grapheme_cell_width(grapheme, config))
+        .sum()
+}
+
+fn advances_are_uniform(advances: &[f32]) -> bool {
+    let min = advances.iter().cloned().fold(std::f32::INFINITY, f32::min);
+    let max = advances
+        .iter()
+        .cloned()
+        .fold(std::f32::NEG_INFINITY, f32::max);
+    advances.is_empty() || max - min <= MONOSPACE_ADVANCE_TOLERANCE
+}
+
+// Equally common advances are decided in favour of the narrower one, so the cell width doesn't
+// depend on the hash map's iteration order
+fn most_common_advance(advances: &[f32]) -> Option<f32> {
+    let mut amounts = HashMap::new();
+
+    for advance in advances.iter() {
+        amounts
+            .entry(OrderedF32::new(*advance))
+            .and_modify(|e| *e += 1)
+            .or_insert(1);
+    }
+
+    amounts
+        .into_iter()
+        .max_by(|(advance, count), (other_advance, other_count)| {
+            count.cmp(other_count).then_with(|| {
+                other_advance
+                    .value()
+                    .partial_cmp(&advance.value())
+                    .unwrap_or(Ordering::Equal)
+            })
+        })
+        .map(|(advance, _)| advance.value())
+}
+
+impl CachingShaper {
+    pub fn new(config: ShaperConfig, cache_settings: CacheSettings) -> CachingShaper {
+        warn_unsupported_features(&config.features);
+        let cache_settings = cache_settings.validated();
+        let options = FontOptions::new(String::from(SYSTEM_DEFAULT_FONT), DEFAULT_FONT_SIZE);
+        let mut loader = FontLoader::new();
+        let font_set = FontSet::new(
+            &options.fallback_list,
+            &options.fallback_rules,
+            &mut loader,
+            &config,
+        );
+
+        CachingShaper {
+            options,
+            config,
+            font_set,
+            font_loader: loader,
+            font_cache: LruCache::new(cache_settings.font_capacity),
+            blob_cache: BlobCache::new(
+                cache_settings.blob_capacity,
+                cache_settings.blob_byte_budget,
+            ),
+            missing_codepoints: Vec::new(),
+            cell_metrics_cache: HashMap::new(),
+            vertical_metrics_cache: HashMap::new(),
+            stylistic_substitutions: HashMap::new(),
+            scale_factor: 1.0,
+            trace_shaping: *SHAPE_TRACE,
+            stats: CacheStats::default(),
+            font_generation: 0,
+            #[cfg(test)]
+            chunks_shaped: 0,
+            _not_send: PhantomData,
+        }
+    }
+
+    fn get_skia_font(
+        &mut self,
+        skribo_font: &SkriboFont,
+        bold: bool,
+        italic: bool,
+    ) -> Option<&SkiaFont> {
+        let font_key = SkiaFontKey::new(
+            skribo_font.font.postscript_name()?,
+            OrderedF32::new(self.pixel_size()),
+            needs_synthetic_italic(&skribo_font.font, italic),
+        );
+
+        if self.trace_shaping {
+            trace!(
+                "Font cache {} for {:?}",
+                if self.font_cache.contains(&font_key) {
+                    "hit"
+                } else {
+                    "miss"
+                },
+                font_key
+            );
+        }
+
+        if self.font_cache.contains(&font_key) {
+            self.stats.font_hits += 1;
+        } else {
+            self.stats.font_misses += 1;
+            let font = build_skia_font_from_skribo_font(skribo_font, &font_key, &self.config)?;
+            self.font_cache.put(font_key.clone(), font);
+        }
+
+        self.font_cache.get(&font_key)
+    }
+
+    fn primary_font(&self) -> &Font {
+        &self
+            .font_set
+            .normal
+            .itemize("a")
+            .next()
+            .expect("Cannot get font metrics")
+            .1
+            .font
+    }
+
+    fn metrics(&self) -> Metrics {
+        self.primary_font().metrics()
+    }
+
+    pub fn scale_factor(&self) -> f32 {
+        self.scale_factor
+    }
+
+    // The font size and spacing in device pixels
+    fn pixel_size(&self) -> f32 {
+        self.options.size * self.scale_factor
+    }
+
+    fn letter_spacing(&self) -> f32 {
+        self.config.letter_spacing * self.scale_factor
+    }
+
+    fn line_spacing(&self) -> f32 {
+        self.config.line_spacing * self.scale_factor
+    }
+
+    fn ligatures(&self) -> bool {
+        self.config.ligatures(&self.options.features)
+    }
+
+    fn kerning(&self) -> bool {
+        self.config.kerning(&self.options.features)
+    }
+
+    // Width of a cell with the letter spacing, which every cell of text is advanced by
+    fn cell_advance(&self) -> Option<f32> {
+        self.reference_advance()
+            .map(|font_width| (font_width + self.letter_spacing()).max(1.0))
+    }
+
+    fn reference_advance(&self) -> Option<f32> {
+        let font = self.primary_font();
+        let units_per_em = font.metrics().units_per_em as f32;
+
+        MONOSPACE_REFERENCE_CHARACTERS.iter().find_map(|character| {
+            let glyph_id = font
+                .glyph_for_char(*character)
+                .filter(|glyph_id| *glyph_id != 0)?;
+            let advance = font.advance(glyph_id).ok()?;
+            Some(advance.x() * self.pixel_size() / units_per_em)
+        })
+    }
+
+    fn layout_runs(&self, text: &str, bold: bool, italic: bool) -> Vec<FontRun> {
+        match self.single_ascii_run(&self.font_set, text, bold, italic) {
+            Some(run) => vec![run],
+            None => self.layout_runs_with_skribo(&self.font_set, text, bold, italic),
+        }
+    }
+
+    // Single ASCII characters make up most of the grid. Their glyph comes straight from the
+    // primary font's character map, which gives the same result as laying them out when they
+    // are snapped to a cell. Characters with a width override take the full layout instead
+    fn single_ascii_run(
+        &self,
+        font_set: &FontSet,
+        text: &str,
+        bold: bool,
+        italic: bool,
+    ) -> Option<FontRun> {
+        let mut characters = text.chars();
+        let character = characters
+            .next()
+            .filter(|character| character.is_ascii_graphic())?;
+        if characters.next().is_some()
+            || self.config.subpixel
+            || self.config.width_overrides.contains_key(&character)
+        {
+            return None;
+        }
+
+        let cell_advance = self.cell_advance()?;
+        let (_, font) = font_set.get(bold, italic).itemize(text).next()?;
+        let (_, font) =
+            apply_fallback_rules(vec![(text, font.clone())], font_set.pinned(bold, italic))
+                .pop()?;
+        if !font_set.is_primary(&font) {
+            return None;
+        }
+        let glyph_id = font
+            .font
+            .glyph_for_char(character)
+            .filter(|glyph_id| *glyph_id != 0)?;
+
+        Some(FontRun {
+            font,
+            primary: true,
+            glyphs: vec![ShapedGlyph::new(glyph_id as u16, Point::new(0.0, 0.0))],
+            advance: cell_advance,
+            cell_width: 1,
+            scale_x: 1.0,
+            missing: Vec::new(),
+        })
+    }
+
+    fn layout_runs_with_skribo(
+        &self,
+        font_set: &FontSet,
+        text: &str,
+        bold: bool,
+        italic: bool,
+    ) -> Vec<FontRun> {
+        let style = TextStyle {
+            size: self.pixel_size(),
+        };
+        let collection = font_set.get(bold, italic);
+        let pinned = font_set.pinned(bold, italic);
+        let letter_spacing = self.letter_spacing();
+        // Snapped glyphs are measured in cells of the primary font, which get spaced out together
+        let cell_snap = match (self.reference_advance(), self.cell_advance()) {
+            (Some(font_width), Some(cell_advance)) => Some((font_width, cell_advance)),
+            _ => None,
+        };
+        let text_snap = if self.config.subpixel {
+            None
+        } else {
+            cell_snap
+        };
+
+        let text = expand_tabs(text, &self.config);
+        let text = no_break_spaces_as_spaces(&text);
+        let text = control_pictures(&text, &self.config);
+        let kerning = self.kerning();
+        let segments = visual_segments(&text, self.ligatures());
+        let segments: Vec<LayoutSegment> = if self.config.box_drawing_snap {
+            segments.into_iter().flat_map(split_box_drawing).collect()
+        } else {
+            segments
+        };
+        let max_shape_len = self.config.max_shape_len;
+        let segments: Vec<LayoutSegment> = segments
+            .into_iter()
+            .flat_map(|segment| bounded_segments(segment, max_shape_len))
+            .collect();
+
+        let mut runs: Vec<FontRun> = Vec::new();
+        let mut x = 0.0;
+
+        for segment in segments {
+            let box_drawing = matches!(segment, LayoutSegment::BoxDrawing(_));
+            let right_to_left = matches!(segment, LayoutSegment::RightToLeft(_));
+            // Marks in clusters are placed by the layout, they have no advance of their own
+            let segment_kerning = kerning || !matches!(segment, LayoutSegment::Text(_));
+            let snap_width = if box_drawing { cell_snap } else { text_snap };
+
+            let presentation_item = match segment {
+                LayoutSegment::Cluster(cluster) => font_set
+                    .presentation_font(collection, cluster)
+                    .map(|font| (cluster, font)),
+                _ => None,
+            };
+            let mut items = match presentation_item {
+                Some(item) => vec![item],
+                None => apply_fallback_rules(itemize_segment(collection, segment), pinned),
+            };
+            if right_to_left {
+                items.reverse();
+            }
+            for (item_text, font) in items {
+                let item_layout = layout_run(&style, &font, item_text);
+                let (glyph_xs, layout_advance) =
+                    glyph_positions(&item_layout, &font.font, style.size, segment_kerning);
+                // Right to left glyphs are laid out in reading order and drawn at the mirrored
+                // positions
+                let draw_xs: Cow<[f32]> = if right_to_left {
+                    Cow::Owned(mirrored_positions(
+                        &item_layout,
+                        &font.font,
+                        style.size,
+                        &glyph_xs,
+                        layout_advance,
+                    ))
+                } else {
+                    Cow::Borrowed(&glyph_xs[..])
+                };
+                let item_cell_width = cell_width(item_text, &self.config);
+                let glyph_count = item_layout.glyphs.len();
+
+                // Box drawing glyphs from fonts with another advance are stretched to fill their
+                // cells exactly, so the line and corner strokes meet at the cell edges
+                let native_advance = layout_advance / item_cell_width.max(1) as f32;
+                let scale_x = match (snap_width, cell_snap) {
+                    (Some((_, cell_advance)), _) if box_drawing && native_advance > 0.0 => {
+                        cell_advance / native_advance
+                    }
+                    (_, Some((_, cell_advance))) if self.config.clamp_wide_glyphs => {
+                        clamped_scale_x(layout_advance, item_cell_width, cell_advance)
+                    }
+                    _ => 1.0,
+                };
+
+                let glyphs = item_layout
+                    .glyphs
+                    .iter()
+                    .zip(draw_xs.iter())
+                    .enumerate()
+                    .map(|(index, (glyph, glyph_x))| {
+                        let offset = match snap_width {
+                            Some((font_width, cell_advance)) => {
+                                let cell = nearest_cell(*glyph_x, font_width, item_cell_width);
+                                cell as f32 * cell_advance
+                            }
+                            None => glyph_x * scale_x + index as f32 * letter_spacing,
+                        };
+                        // Layout offsets point up while skia's y axis points down
+                        let position = Point::new(x + offset, -glyph.offset.y());
+                        ShapedGlyph::new(glyph.glyph_id as u16, position)
+                    });
+
+                let item_advance = match snap_width {
+                    Some((_, cell_advance)) => item_cell_width as f32 * cell_advance,
+                    None => layout_advance * scale_x + glyph_count as f32 * letter_spacing,
+                };
+
+                let in_missing_glyph_font = font_set.is_missing_glyph_font(&font);
+                let item_missing: Vec<char> = item_text
+                    .chars()
+                    .filter(|character| {
+                        !is_invisible(*character)
+                            && (in_missing_glyph_font || !font_has_glyph(&font.font, *character))
+                    })
+                    .collect();
+
+                match runs.last_mut() {
+                    Some(run)
+                        if run.font.font.full_name() == font.font.full_name()
+                            && (run.scale_x - scale_x).abs() < std::f32::EPSILON =>
+                    {
+                        run.glyphs.extend(glyphs);
+                        run.advance += item_advance;
+                        run.cell_width += item_cell_width;
+                        run.missing.extend(item_missing);
+                    }
+                    _ => runs.push(FontRun {
+                        primary: font_set.is_primary(&font),
+                        font,
+                        glyphs: glyphs.collect(),
+                        advance: item_advance,
+                        cell_width: item_cell_width,
+                        scale_x,
+                        missing: item_missing,
+                    }),
+                }
+
+                x += item_advance;
+            }
+        }
+
+        runs
+    }
+
+    pub fn shape(&mut self, text: &str, style: FontStyle) -> Vec<ShapedRun> {
+        let (bold, italic) = (style.bold(), style.italic());
+        let baseline = self.snapped_cell_metrics().baseline;
+        let (font_width, _) = self.cell_metrics();
+        let mut shaped_runs = Vec::new();
+
+        if let Some(whitespace_run) = self.whitespace_run(text, baseline) {
+            shaped_runs.push(ShapedRun {
+                reverse: style.reverse(),
+                ..whitespace_run
+            });
+            return shaped_runs;
+        }
+
+        let mut runs = self.layout_runs(text, bold, italic);
+        let stylistic_sets = self.config.stylistic_sets(&self.options.features);
+        if !stylistic_sets.is_empty() {
+            for run in runs.iter_mut() {
+                let substitutions =
+                    self.font_stylistic_substitutions(&run.font.font, &stylistic_sets);
+                substitute_glyphs(&mut run.glyphs, &substitutions);
+            }
+        }
+
+        for run in runs {
+            let used_fallback = !run.primary;
+            let synthetic_bold_stroke = if needs_synthetic_bold(&run.font.font, bold) {
+                Some(synthetic_bold_stroke_width(
+                    self.config.synthetic_bold_strength,
+                    self.pixel_size(),
+                ))
+            } else {
+                None
+            };
+            for character in run.missing.iter() {
+                if !self.missing_codepoints.contains(character) {
+                    warn!(
+                        "No font has a glyph for U+{:04X}, a font covering it could be added to guifont",
+                        *character as u32
+                    );
+                    self.missing_codepoints.push(*character);
+                }
+            }
+
+            let is_color =
+                self.font_set.is_emoji_font(&run.font) || has_color_glyphs(&run.font.font);
+            let notdef_run = self.font_set.is_missing_glyph_font(&run.font)
+                || run.glyphs.iter().all(|glyph| glyph.glyph_id == 0);
+            if self.config.draw_missing_codepoint && notdef_run {
+                if let Some(shaped_run) = self.codepoint_box_run(&run, baseline) {
+                    shaped_runs.push(ShapedRun {
+                        reverse: style.reverse(),
+                        ..shaped_run
+                    });
+                    continue;
+                }
+            }
+
+            if let Some(skia_font) = self.get_skia_font(&run.font, bold, italic) {
+                let mut skia_font = skia_font.clone();
+                skia_font.set_scale_x(run.scale_x);
+
+                if let Some(blob) = make_blob(&skia_font, &run.glyphs) {
+                    shaped_runs.push(ShapedRun {
+                        font_name: run.font.font.full_name(),
+                        blob: Some(blob),
+                        advance: run.advance,
+                        cell_width: run.cell_width,
+                        clip_width: run.cell_width as f32 * font_width,
+                        used_fallback,
+                        has_missing_glyphs: !run.missing.is_empty(),
+                        glyph_count: run.glyphs.len(),
+                        decorations: decoration_metrics(&skia_font, baseline),
+                        synthetic_bold_stroke,
+                        blend: 0,
+                        codepoint_boxes: Vec::new(),
+                        is_color,
+                        baseline,
+                        reverse: style.reverse(),
+                    });
+                }
+            } else {
+                warn!("Could not load skribo font");
+            }
+        }
+
+        if self.trace_shaping {
+            for shaped_run in shaped_runs.iter() {
+                trace!(
+                    "Shaped {:?} into {} glyphs from {}, fallback: {}, missing glyphs: {}",
+                    text,
+                    shaped_run.glyph_count,
+                    shaped_run.font_name,
+                    shaped_run.used_fallback,
+                    shaped_run.has_missing_glyphs
+                );
+            }
+        }
+
+        shaped_runs
+    }
+
+    fn font_stylistic_substitutions(&mut self, font: &Font, sets: &[u8]) -> Rc<HashMap<u16, u16>> {
+        self.stylistic_substitutions
+            .entry((font.full_name(), sets.to_vec()))
+            .or_insert_with(|| {
+                Rc::new(
+                    font.load_font_table(GSUB_TABLE_TAG)
+                        .map(|gsub| stylistic_substitutions(&gsub, sets))
+                        .unwrap_or_default(),
+                )
+            })
+            .clone()
+    }
+
+    // Indentation and trailing whitespace only take up cells, so they skip layout entirely. The
+    // primary font's decorations are kept since underlines still span them
+    fn whitespace_run(&mut self, text: &str, baseline: f32) -> Option<ShapedRun> {
+        if text.is_empty() || !text.chars().all(is_blank) {
+            return None;
+        }
+
+        let cell_count = cell_width(&expand_tabs(text, &self.config), &self.config);
+        let (font_width, _) = self.cell_metrics();
+        let cell_advance = self.cell_advance().unwrap_or(font_width);
+        let primary_font = self.font_set.normal.itemize(" ").next()?.1.clone();
+        let primary_skia_font = self.get_skia_font(&primary_font, false, false)?;
+
+        Some(ShapedRun {
+            font_name: primary_font.font.full_name(),
+            blob: None,
+            advance: cell_count as f32 * cell_advance,
+            cell_width: cell_count,
+            clip_width: cell_count as f32 * cell_advance,
+            used_fallback: false,
+            has_missing_glyphs: false,
+            glyph_count: 0,
+            decorations: decoration_metrics(primary_skia_font, baseline),
+            synthetic_bold_stroke: None,
+            blend: 0,
+            codepoint_boxes: Vec::new(),
+            is_color: false,
+            baseline,
+            reverse: false,
+        })
+    }
+
+    // Replaces the boxes of the missing glyph font with the code point of every missing character,
+    // written in the primary font and sized to fit the character's cells
+    fn codepoint_box_run(&mut self, run: &FontRun, baseline: f32) -> Option<ShapedRun> {
+        let (font_width, font_height) = self.cell_metrics();
+        let cell_advance = run.advance / run.cell_width.max(1) as f32;
+        let inset = (font_height / 10.0).max(1.0);
+        let primary_font = self.font_set.normal.itemize("0").next()?.1.clone();
+        let primary_skia_font = self.get_skia_font(&primary_font, false, false)?.clone();
+        let mut digit_font = primary_skia_font.clone();
+
+        let mut blob_builder = TextBlobBuilder::new();
+        let mut codepoint_boxes = Vec::new();
+        let mut glyph_count = 0;
+        let mut x = run.glyphs.first()?.position.x;
+        for character in run.missing.iter() {
+            let mut buffer = [0; 4];
+            let cells =
+                grapheme_cell_width(character.encode_utf8(&mut buffer), &self.config).max(1);
+            let box_width = cells as f32 * cell_advance;
+            let codepoint_box = Rect::new(
+                x + inset / 2.0,
+                inset / 2.0,
+                x + box_width - inset / 2.0,
+                font_height - inset / 2.0,
+            );
+
+            let hex = format!("{:04X}", *character as u32);
+            digit_font.set_size(primary_skia_font.size());
+            let (natural_width, _) = digit_font.measure_str(&hex, None);
+            let digit_size = (primary_skia_font.size() * (codepoint_box.width() - inset)
+                / natural_width.max(1.0))
+            .min(codepoint_box.height() / 2.0)
+            .max(1.0);
+            digit_font.set_size(digit_size);
+
+            let (text_width, _) = digit_font.measure_str(&hex, None);
+            let (_, digit_metrics) = digit_font.metrics();
+            // The boxes are relative to the top of the row, the digits to the baseline
+            let origin = Point::new(
+                codepoint_box.center_x() - text_width / 2.0,
+                (font_height + digit_metrics.cap_height) / 2.0 - baseline,
+            );
+            let glyph_ids = digit_font.str_to_glyphs_vec(&hex);
+            blob_builder
+                .alloc_run(&digit_font, glyph_ids.len(), origin, None)
+                .copy_from_slice(&glyph_ids);
+
+            glyph_count += glyph_ids.len();
+            codepoint_boxes.push(codepoint_box);
+            x += box_width;
+        }
+
+        Some(ShapedRun {
+            font_name: run.font.font.full_name(),
+            blob: Some(blob_builder.make()?),
+            advance: run.advance,
+            cell_width: run.cell_width,
+            clip_width: run.cell_width as f32 * font_width,
+            used_fallback: true,
+            has_missing_glyphs: true,
+            glyph_count,
+            decorations: decoration_metrics(&primary_skia_font, baseline),
+            synthetic_bold_stroke: None,
+            blend: 0,
+            codepoint_boxes,
+            is_color: false,
+            baseline,
+            reverse: false,
+        })
+    }
+
+    pub fn shape_cached(&mut self, text: &str, style: FontStyle) -> Rc<Vec<ShapedRun>> {
+        self.shape_cached_blended(text, style, 0)
+    }
+
+    fn shape_key(&self, text: &str, style: FontStyle) -> ShapeKey {
+        ShapeKey::new(
+            text.to_string(),
+            OrderedF32::new(self.options.size),
+            OrderedF32::new(self.scale_factor),
+            style.cache_key_style(),
+        )
+    }
+
+    // Like shape_cached, but the runs carry the blend of the highlight they are drawn with
+    pub fn shape_cached_blended(
+        &mut self,
+        text: &str,
+        style: FontStyle,
+        blend: u8,
+    ) -> Rc<Vec<ShapedRun>> {
+        let mut key = self.shape_key(text, style);
+        key.blend = blend;
+        self.shape_cached_by_key(key)
+    }
+
+    fn shape_cached_by_key(&mut self, key: ShapeKey) -> Rc<Vec<ShapedRun>> {
+        if self.trace_shaping {
+            trace!(
+                "Blob cache {} for {:?}",
+                if self.blob_cache.contains(&key) {
+                    "hit"
+                } else {
+                    "miss"
+                },
+                key
+            );
+        }
+
+        if self.blob_cache.contains(&key) {
+            self.stats.blob_hits += 1;
+        } else {
+            self.stats.blob_misses += 1;
+            let mut blobs = self.shape(&key.text, key.style);
+            for blob in blobs.iter_mut() {
+                blob.blend = key.blend;
+            }
+            self.blob_cache.put(key.clone(), blobs);
+        }
+
+        self.blob_cache.get(&key).unwrap()
+    }
+
+    // Shapes the first grapheme of the text on its own, for redrawing a single cell like the one
+    // under a block cursor. Graphemes are laid out in their cell the same way as within a line.
+    // A grapheme split across fonts only hands back the run of its base character
+    pub fn shape_single_cell(&mut self, text: &str, style: FontStyle) -> Option<ShapedRun> {
+        let grapheme = text.graphemes(true).next()?;
+        self.shape_cached(grapheme, style).first().cloned()
+    }
+
+    // Shapes a line which changed from the previous one, reusing the chunks before and after the
+    // edit. Only the chunks in between are shaped again, the ones after it just move over. Chunks
+    // are at most max_shape_len graphemes, so long lines are cached in pieces too. Tabs are
+    // expanded up front so tab stops still count from the start of the line
+    pub fn shape_line_incremental(
+        &mut self,
+        previous: &[LineChunk],
+        line: &str,
+        style: FontStyle,
+        blend: u8,
+    ) -> Vec<LineChunk> {
+        let line = expand_tabs(line, &self.config);
+        let max_shape_len = self.config.max_shape_len;
+        let chunks: Vec<&str> = line_chunks(&line)
+            .into_iter()
+            .flat_map(|chunk| bounded_chunks(chunk, max_shape_len))
+            .collect();
+        let font_generation = self.font_generation;
+        let reusable = |chunk: &&str, previous_chunk: &LineChunk| {
+            *chunk == previous_chunk.text
+                && previous_chunk.style == style
+                && previous_chunk.blend == blend
+                && previous_chunk.font_generation == font_generation
+        };
+        let prefix = chunks
+            .iter()
+            .zip(previous.iter())
+            .take_while(|(chunk, previous_chunk)| reusable(chunk, previous_chunk))
+            .count();
+        let suffix = chunks[prefix..]
+            .iter()
+            .rev()
+            .zip(previous[prefix..].iter().rev())
+            .take_while(|(chunk, previous_chunk)| reusable(chunk, previous_chunk))
+            .count();
+
+        let mut shaped_chunks = Vec::new();
+        let mut cell_offset = 0;
+        for (index, chunk) in chunks.iter().enumerate() {
+            let runs = if index < prefix {
+                previous[index].runs.clone()
+            } else if index >= chunks.len() - suffix {
+                previous[previous.len() - (chunks.len() - index)]
+                    .runs
+                    .clone()
+            } else {
+                #[cfg(test)]
+                {
+                    self.chunks_shaped += 1;
+                }
+                self.shape_cached_blended(chunk, style, blend).to_vec()
+            };
+
+            shaped_chunks.push(LineChunk {
+                text: chunk.to_string(),
+                style,
+                blend,
+                font_generation,
+                cell_offset,
+                runs,
+            });
+            cell_offset += cell_width(chunk, &self.config);
+        }
+        shaped_chunks
+    }
+
+    // Fonts glyphs are looked up in for the style, in order, for finding out where a glyph came
+    // from. Families which couldn't be loaded are left out
+    pub fn fallback_chain(&self, style: FontStyle) -> Vec<String> {
+        self.font_set.chain(style.bold(), style.italic()).to_vec()
+    }
+
+    // Traces how the caches did over the last frame, and counts the next one from zero
+    pub fn trace_stats(&mut self) {
+        let stats = self.stats;
+        trace!(
+            "Shaper caches: fonts {} hits {} misses, blobs {} hits {} misses, cell metrics {} hits {} misses",
+            stats.font_hits,
+            stats.font_misses,
+            stats.blob_hits,
+            stats.blob_misses,
+            stats.cell_metrics_hits,
+            stats.cell_metrics_misses
+        );
+        self.stats = CacheStats::default();
+    }
+
+    pub fn update_font(&mut self, guifont_setting: &str) -> bool {
+        let previous_fallback_list = self.options.fallback_list.clone();
+        let previous_fallback_rules = self.options.fallback_rules.clone();
+        let previous_features = self.options.features.clone();
+        let updated = self.options.update(guifont_setting);
+        if updated {
+            trace!("Font changed: {:?}", self.options);
+            self.font_generation += 1;
+            self.stylistic_substitutions.clear();
+
+            if self.options.features != previous_features {
+                warn_unsupported_features(&self.options.features);
+                self.blob_cache.clear();
+            }
+
+            // Cached fonts and blobs are keyed by size, so they only go stale when the families
+            // themselves or the ranges pinned to them change
+            if self.options.fallback_list != previous_fallback_list
+                || self.options.fallback_rules != previous_fallback_rules
+            {
+                // A family which went missing may have been installed since it was asked for
+                if self
+                    .options
+                    .fallback_list
+                    .iter()
+                    .any(|family| self.font_loader.missing.contains(family))
+                {
+                    self.font_loader.refresh();
+                }
+                self.font_set = FontSet::new(
+                    &self.options.fallback_list,
+                    &self.options.fallback_rules,
+                    &mut self.font_loader,
+                    &self.config,
+                );
+                trace!(
+                    "Fallback chain: {:?}",
+                    self.fallback_chain(FontStyle::empty())
+                );
+                self.font_cache.clear();
+                self.blob_cache.clear();
+                self.cell_metrics_cache.clear();
+                self.vertical_metrics_cache.clear();
+            }
+        }
+        updated
+    }
+
+    // Builds the skia fonts for the primary font's faces and measures the cells ahead of the
+    // first frame, so drawing it doesn't stall on them. Preloading again is a no-op
+    pub fn preload(&mut self) {
+        for (bold, italic) in [(false, false), (true, false), (false, true)].iter() {
+            let primary_font = self
+                .font_set
+                .get(*bold, *italic)
+                .itemize("a")
+                .next()
+                .map(|(_, font)| font.clone());
+            if let Some(primary_font) = primary_font {
+                self.get_skia_font(&primary_font, *bold, *italic);
+            }
+        }
+        self.cell_metrics();
+    }
+
+    // Switches to the fonts and size of a guifont setting and hands back the resulting cell size,
+    // or None when the setting didn't change anything
+    pub fn apply_guifont(&mut self, guifont_setting: &str) -> Option<(f32, f32)> {
+        if self.update_font(guifont_setting) {
+            Some(self.font_base_dimensions())
+        } else {
+            None
+        }
+    }
+
+    // Glyphs land on other device pixels after moving to a monitor with another scale factor, so
+    // shaped text is dropped. Skia fonts are keyed by their size in device pixels and stay loaded.
+    // Hands back the cell size in logical pixels for the new scale
+    pub fn on_scale_change(&mut self, scale_factor: f32) -> (f32, f32) {
+        if (self.scale_factor - scale_factor).abs() > std::f32::EPSILON {
+            self.scale_factor = scale_factor;
+            self.font_generation += 1;
+            self.blob_cache.clear();
+        }
+        self.font_base_dimensions()
+    }
+
+    // The cell size in logical pixels, which the window lays out the grid in. The height is
+    // rounded up to whole device pixels so that rows don't accumulate rounding errors down the
+    // window. Widths are left alone since glyphs are snapped to them
+    pub fn font_base_dimensions(&mut self) -> (f32, f32) {
+        let snapped = self.snapped_cell_metrics();
+        (
+            snapped.font_width / self.scale_factor,
+            snapped.snapped_row_height / self.scale_factor,
+        )
+    }
+
+    // Cell metrics in device pixels. The space added by snapping is split above and below the
+    // text, and the baseline is put on a whole device pixel so every row draws its glyphs the
+    // same way
+    pub fn snapped_cell_metrics(&mut self) -> SnappedCellMetrics {
+        let (font_width, font_height) = self.cell_metrics();
+        let snapped_row_height = font_height.ceil();
+        let baseline = self.vertical_metrics().ascent + (snapped_row_height - font_height) / 2.0;
+        let baseline = baseline.round().min(snapped_row_height);
+        SnappedCellMetrics {
+            font_width,
+            snapped_row_height,
+            baseline,
+        }
+    }
+
+    // In device pixels
+    pub fn cell_metrics(&mut self) -> (f32, f32) {
+        let key = (
+            OrderedF32::new(self.options.size),
+            OrderedF32::new(self.scale_factor),
+        );
+        if let Some(cell_metrics) = self.cell_metrics_cache.get(&key) {
+            self.stats.cell_metrics_hits += 1;
+            return *cell_metrics;
+        }
+
+        self.stats.cell_metrics_misses += 1;
+        let cell_metrics = self.measure_cell_metrics();
+        self.cell_metrics_cache.insert(key, cell_metrics);
+        cell_metrics
+    }
+
+    // Geometry of the primary font, which the cell height and the baseline are taken from
+    pub fn vertical_metrics(&mut self) -> VerticalMetrics {
+        let key = OrderedF32::new(self.pixel_size());
+        if let Some(vertical_metrics) = self.vertical_metrics_cache.get(&key) {
+            return *vertical_metrics;
+        }
+
+        let metrics = self.metrics();
+        let scale = self.pixel_size() / metrics.units_per_em as f32;
+        let vertical_metrics = VerticalMetrics {
+            ascent: metrics.ascent * scale,
+            descent: metrics.descent * scale,
+        };
+        self.vertical_metrics_cache.insert(key, vertical_metrics);
+        vertical_metrics
+    }
+
+    // Advances between neighbouring glyphs of the measurement string in the primary font. Glyphs
+    // from fallback fonts don't say anything about the grid's width
+    fn measurement_advances(&self) -> Vec<f32> {
+        let style = TextStyle {
+            size: self.pixel_size(),
+        };
+        let session = LayoutSession::create(
+            &self.config.measurement_string,
+            &style,
+            &self.font_set.normal,
+        );
+
+        let mut advances = Vec::new();
+        for layout_run in session.iter_all() {
+            if !self.font_set.is_primary(layout_run.font()) {
+                continue;
+            }
+
+            let glyph_offsets: Vec<f32> =
+                layout_run.glyphs().map(|glyph| glyph.offset.x()).collect();
+            advances.extend(glyph_offsets.windows(2).map(|pair| pair[1] - pair[0]));
+        }
+        advances
+    }
+
+    fn metrics_cache(&mut self) -> MetricsCache {
+        MetricsCache {
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            font_name: self.primary_font().full_name(),
+            measurement_string: self.config.measurement_string.clone(),
+            size: self.options.size,
+            scale_factor: self.scale_factor,
+            letter_spacing: self.config.letter_spacing,
+            line_spacing: self.config.line_spacing,
+            cell_metrics: self.cell_metrics(),
+        }
+    }
+
+    // Writes the primary font's cell size for the next session, meant for a clean shutdown
+    pub fn save_metrics_cache(&mut self, path: &Path) {
+        let metrics_cache = self.metrics_cache();
+        if let Some(directory) = path.parent() {
+            if let Err(error) = fs::create_dir_all(directory) {
+                warn!(
+                    "Could not create font metrics cache directory {:?}: {}",
+                    directory, error
+                );
+                return;
+            }
+        }
+        if let Err(error) = fs::write(path, metrics_cache.serialize()) {
+            warn!("Could not write font metrics cache {:?}: {}", path, error);
+        }
+    }
+
+    // Takes the cell size from a metrics cache saved for the same font and spacing by the same
+    // version, so it isn't measured before the first frame. Returns whether the cache was used
+    pub fn load_metrics_cache(&mut self, path: &Path) -> bool {
+        match fs::read(path)
+            .ok()
+            .and_then(|bytes| MetricsCache::deserialize(&bytes))
+        {
+            Some(metrics_cache) => self.use_metrics_cache(&metrics_cache),
+            None => false,
+        }
+    }
+
+    // The cell size is kept for the size and scale factor it was measured at, so it is picked up
+    // once the window reports that scale factor
+    fn use_metrics_cache(&mut self, metrics_cache: &MetricsCache) -> bool {
+        let matches = metrics_cache.version == env!("CARGO_PKG_VERSION")
+            && metrics_cache.font_name == self.primary_font().full_name()
+            && metrics_cache.measurement_string == self.config.measurement_string
+            && (metrics_cache.letter_spacing - self.config.letter_spacing).abs()
+                < std::f32::EPSILON
+            && (metrics_cache.line_spacing - self.config.line_spacing).abs() < std::f32::EPSILON;
+        if matches {
+            self.cell_metrics_cache.insert(
+                (
+                    OrderedF32::new(metrics_cache.size),
+                    OrderedF32::new(metrics_cache.scale_factor),
+                ),
+                metrics_cache.cell_metrics,
+            );
+        } else {
+            trace!("Ignoring font metrics cache from another font or spacing");
+        }
+        matches
+    }
+
+    fn measure_cell_metrics(&mut self) -> (f32, f32) {
+        let vertical_metrics = self.vertical_metrics();
+        let font_height = vertical_metrics.ascent - vertical_metrics.descent + self.line_spacing();
+        let glyph_advances = self.measurement_advances();
+
+        if !advances_are_uniform(&glyph_advances) {
+            warn!(
+                "Glyph advances of {:?} vary, it is likely not a monospace font",
+                self.options.fallback_list
+            );
+        }
+
+        let font_width = self.cell_advance().unwrap_or_else(|| {
+            most_common_advance(&glyph_advances).unwrap_or_else(|| {
+                warn!("Could not measure font width, guessing from the font size");
+                self.pixel_size() / 2.0
+            }) + self.letter_spacing()
+        });
+
+        (font_width.max(1.0), font_height.max(1.0))
+    }
+}
+
+impl Default for CachingShaper {
+    fn default() -> CachingShaper {
+        CachingShaper::new(ShaperConfig::default(), CacheSettings::default())
+    }
+}
+
+// What the renderer needs from a shaper, so drawing can be exercised without a font system
+pub trait TextShaper {
+    // Shapes a fragment of a line, reusing the chunks of the text drawn there before
+    fn shape_line(
+        &mut self,
+        previous: &[LineChunk],
+        text: &str,
+        style: FontStyle,
+        blend: u8,
+    ) -> Vec<LineChunk>;
+    fn cell_metrics(&mut self) -> (f32, f32);
+    // Runs come in device pixels, which are this many logical pixels
+    fn scale_factor(&self) -> f32;
+}
+
+impl TextShaper for CachingShaper {
+    fn shape_line(
+        &mut self,
+        previous: &[LineChunk],
+        text: &str,
+        style: FontStyle,
+        blend: u8,
+    ) -> Vec<LineChunk> {
+        self.shape_line_incremental(previous, text, style, blend)
+    }
+
+    fn cell_metrics(&mut self) -> (f32, f32) {
+        CachingShaper::cell_metrics(self)
+    }
+
+    fn scale_factor(&self) -> f32 {
+        CachingShaper::scale_factor(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use skulpin::skia_safe::{ImageInfo, Surface};
+
+    // Proportional fonts which ship with the system but aren't among the default fallbacks
+    define! {
+        if #[cfg(target_os = "windows")] {
+            const SECOND_TEST_FONT: &str = "Arial";
+        } else if #[cfg(target_os = "linux")] {
+            const SECOND_TEST_FONT: &str = "DejaVu Sans";
+        } else if #[cfg(target_os = "macos")] {
+            const SECOND_TEST_FONT: &str = "Helvetica";
+        }
+    }
+
+    lazy_static! {
+        static ref CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS.lock().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger;
+
+    #[cfg(feature = "embed-fonts")]
+    fn asset_skia_font(font_name: &str) -> SkiaFont {
+        let font_data = Asset::get(font_name).unwrap();
+        let typeface = Typeface::from_data(Data::new_copy(&font_data), None).unwrap();
+        SkiaFont::from_typeface(typeface, DEFAULT_FONT_SIZE)
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    fn asset_skribo_font(font_name: &str) -> SkriboFont {
+        let font_data = Asset::get(font_name).unwrap();
+        SkriboFont::new(Font::from_bytes(font_data.to_vec().into(), 0).unwrap())
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_make_blob_uses_every_input_glyph() {
+        let skia_font = asset_skia_font(MISSING_GLYPH_FONT);
+
+        let one_glyph =
+            make_blob(&skia_font, &[ShapedGlyph::new(1, Point::new(0.0, 0.0))]).unwrap();
+        let two_glyphs = make_blob(
+            &skia_font,
+            &[
+                ShapedGlyph::new(1, Point::new(0.0, 0.0)),
+                ShapedGlyph::new(1, Point::new(20.0, 0.0)),
+            ],
+        )
+        .unwrap();
+
+        assert!(!two_glyphs.bounds().is_empty());
+        assert!(two_glyphs.bounds().width() > one_glyph.bounds().width());
+        assert!(make_blob(&skia_font, &[]).is_none());
+    }
+
+    #[test]
+    fn test_missing_emoji_family_does_not_panic() {
+        let config = ShaperConfig {
+            emoji_family: Some(String::from("Not An Installed Emoji Font")),
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+
+        let (font_width, font_height) = shaper.font_base_dimensions();
+        assert!(font_width > 0.0);
+        assert!(font_height > 0.0);
+    }
+
+    #[test]
+    fn test_missing_primary_font_falls_back() {
+        let mut shaper = CachingShaper::default();
+        shaper.update_font("Nonsense Font Family That Does Not Exist:h14");
+
+        assert!(!shaper.shape("abc", FontStyle::empty()).is_empty());
+
+        let (font_width, font_height) = shaper.font_base_dimensions();
+        assert!(font_width > 0.0);
+        assert!(font_height > 0.0);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_bundled_font_is_the_last_resort_primary() {
+        let mut loader = FontLoader::new();
+        // A family without faces stands in for a system without the default font
+        loader
+            .cache
+            .put(String::from(SYSTEM_DEFAULT_FONT), ExtendedFontFamily::new());
+        let (collection, primary_font_names, _) = build_collection_by_font_name(
+            &mut loader,
+            &ShaperConfig::default(),
+            &[String::from("Nonsense Font Family That Does Not Exist")],
+            false,
+            false,
+        );
+
+        let bundled_font = asset_skribo_font(EXTRA_SYMBOL_FONT);
+        assert_eq!(primary_font_names, vec![bundled_font.font.full_name()]);
+        let (_, font) = collection.itemize("\u{25ca}").next().unwrap();
+        assert_eq!(font.font.full_name(), bundled_font.font.full_name());
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_fallback_list_adds_every_family() {
+        // The bundled fonts are only default fallbacks, so only the guifont can make one primary
+        let second_font_name = asset_skribo_font(MISSING_GLYPH_FONT).font.full_name();
+        let mut shaper = CachingShaper::default();
+        assert!(!shaper
+            .font_set
+            .primary_font_names
+            .contains(&second_font_name));
+
+        shaper.update_font(&format!(
+            "{},{}:h14",
+            SYSTEM_DEFAULT_FONT, MISSING_GLYPH_FONT
+        ));
+
+        assert_eq!(
+            shaper.options.fallback_list,
+            vec![
+                String::from(SYSTEM_DEFAULT_FONT),
+                String::from(MISSING_GLYPH_FONT)
+            ]
+        );
+        assert!(shaper
+            .font_set
+            .primary_font_names
+            .contains(&second_font_name));
+    }
+
+    #[test]
+    fn test_cache_capacity_evicts() {
+        let cache_settings = CacheSettings {
+            font_capacity: 0,
+            blob_capacity: 1,
+            ..CacheSettings::default()
+        };
+        let mut shaper = CachingShaper::new(ShaperConfig::default(), cache_settings);
+
+        shaper.shape_cached("a", FontStyle::empty());
+        shaper.shape_cached("b", FontStyle::empty());
+
+        assert!(!shaper
+            .blob_cache
+            .contains(&shaper.shape_key("a", FontStyle::empty())));
+        assert!(shaper
+            .blob_cache
+            .contains(&shaper.shape_key("b", FontStyle::empty())));
+    }
+
+    #[test]
+    fn test_stats_count_blob_hits_and_misses() {
+        let mut shaper = CachingShaper::default();
+        shaper.shape_cached("warmup", FontStyle::empty());
+        // Tracing the frame's stats starts counting the next frame from zero
+        shaper.trace_stats();
+        assert_eq!(shaper.stats, CacheStats::default());
+
+        shaper.shape_cached("abc", FontStyle::empty());
+        shaper.shape_cached("abc", FontStyle::empty());
+
+        let stats = shaper.stats;
+        assert_eq!(stats.blob_misses, 1);
+        assert_eq!(stats.blob_hits, 1);
+    }
+
+    #[test]
+    fn test_ordered_f32_keys() {
+        assert_eq!(OrderedF32::new(12.0), OrderedF32::new(12.00));
+        assert_eq!(OrderedF32::new(0.0), OrderedF32::new(-0.0));
+        assert_ne!(OrderedF32::new(12.0), OrderedF32::new(12.5));
+        assert!((OrderedF32::new(12.5).value() - 12.5).abs() < std::f32::EPSILON);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_synthesizes_missing_styles() {
+        let mut shaper = CachingShaper::default();
+        let regular_only = asset_skribo_font(MISSING_GLYPH_FONT);
+
+        let plain = shaper.get_skia_font(&regular_only, false, false).unwrap();
+        assert!(plain.skew_x().abs() < std::f32::EPSILON);
+
+        let italic = shaper.get_skia_font(&regular_only, false, true).unwrap();
+        let expected_skew = synthetic_italic_skew(&regular_only.font);
+        assert!((italic.skew_x() - expected_skew).abs() < std::f32::EPSILON);
+
+        assert!(needs_synthetic_bold(&regular_only.font, true));
+        assert!(!needs_synthetic_bold(&regular_only.font, false));
+    }
+
+    #[test]
+    fn test_ligature_features_can_be_disabled() {
+        assert!(ShaperConfig::default().ligatures(&[]));
+
+        let config = ShaperConfig {
+            features: vec![(String::from("calt"), 0), (String::from("liga"), 0)],
+            ..ShaperConfig::default()
+        };
+        assert!(!config.ligatures(&[]));
+
+        let shaper = CachingShaper::new(config, CacheSettings::default());
+        let runs = shaper.layout_runs("!=", false, false);
+        assert_eq!(runs.len(), 1);
+
+        let glyphs = &runs[0].glyphs;
+        assert_eq!(glyphs.len(), 2);
+        assert!(glyphs[1].position.x > glyphs[0].position.x);
+    }
+
+    #[test]
+    #[ignore = "needs DejaVu Sans installed"]
+    fn test_ligature_features_are_turned_off_in_the_font() {
+        // DejaVu Sans joins fi into a single glyph unless ligatures are turned off
+        let config = ShaperConfig {
+            features: vec![(String::from("calt"), 0), (String::from("liga"), 0)],
+            ..ShaperConfig::default()
+        };
+        let mut ligature_shaper = CachingShaper::default();
+        let mut plain_shaper = CachingShaper::new(config, CacheSettings::default());
+        ligature_shaper.update_font("DejaVu Sans:h14");
+        plain_shaper.update_font("DejaVu Sans:h14");
+        assert_eq!(ligature_shaper.primary_font().family_name(), "DejaVu Sans");
+        let glyph_count = |shaper: &CachingShaper| -> usize {
+            shaper
+                .layout_runs("fi", false, false)
+                .iter()
+                .map(|run| run.glyphs.len())
+                .sum()
+        };
+        assert_eq!(glyph_count(&ligature_shaper), 1);
+        assert_eq!(glyph_count(&plain_shaper), 2);
+    }
+
+    #[test]
+    fn test_width_detection_helpers() {
+        let monospace = [8.0, 8.0, 8.0, 8.0];
+        assert!(advances_are_uniform(&monospace));
+        assert!((most_common_advance(&monospace).unwrap() - 8.0).abs() < std::f32::EPSILON);
+
+        let proportional = [4.0, 8.0, 8.0, 12.0];
+        assert!(!advances_are_uniform(&proportional));
+        assert!((most_common_advance(&proportional).unwrap() - 8.0).abs() < std::f32::EPSILON);
+
+        assert!(advances_are_uniform(&[]));
+        assert!(most_common_advance(&[]).is_none());
+    }
+
+    #[test]
+    #[ignore = "needs a proportional system family, DejaVu Sans, Arial or Helvetica"]
+    fn test_proportional_fonts_are_reported() {
+        log::set_logger(&LOGGER).ok();
+        log::set_max_level(log::LevelFilter::Trace);
+        let mut shaper = CachingShaper::default();
+        shaper.update_font(&format!("{}:h17", SECOND_TEST_FONT));
+        assert_eq!(shaper.primary_font().family_name(), SECOND_TEST_FONT);
+        shaper.cell_metrics();
+
+        let logs = CAPTURED_LOGS.lock();
+        assert!(logs.iter().any(|line| line.starts_with("Glyph advances of")
+            && line.contains(SECOND_TEST_FONT)
+            && line.ends_with("it is likely not a monospace font")));
+    }
+
+    #[test]
+    fn test_size_changes_keep_cached_blobs() {
+        let mut shaper = CachingShaper::default();
+
+        shaper.update_font(&format!("{}:h12", SYSTEM_DEFAULT_FONT));
+        shaper.shape_cached("abc", FontStyle::empty());
+        shaper.update_font(&format!("{}:h24", SYSTEM_DEFAULT_FONT));
+        shaper.shape_cached("abc", FontStyle::empty());
+
+        shaper.stats = CacheStats::default();
+        shaper.update_font(&format!("{}:h12", SYSTEM_DEFAULT_FONT));
+        shaper.shape_cached("abc", FontStyle::empty());
+
+        assert_eq!(shaper.stats.blob_hits, 1);
+        assert_eq!(shaper.stats.blob_misses, 0);
+    }
+
+    #[test]
+    fn test_layout_segments_keep_clusters() {
+        use super::LayoutSegment::*;
+
+        assert_eq!(layout_segments("a=>b", true), vec![Text("a=>b")]);
+        assert_eq!(
+            layout_segments("a=>b", false),
+            vec![Text("a"), Text("="), Text(">"), Text("b")]
+        );
+        assert_eq!(
+            layout_segments("ce\u{301}f", true),
+            vec![Text("c"), Cluster("e\u{301}"), Text("f")]
+        );
+    }
+
+    #[test]
+    fn test_combining_mark_shares_base_cell() {
+        let mut shaper = CachingShaper::default();
+        let (font_width, _) = shaper.font_base_dimensions();
+
+        let runs = shaper.layout_runs("e\u{301}", false, false);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].cell_width, 1);
+
+        assert!(runs[0]
+            .glyphs
+            .iter()
+            .all(|glyph| glyph.position.x >= 0.0 && glyph.position.x < font_width));
+    }
+
+    #[test]
+    fn test_wide_characters_take_two_cells() {
+        let config = ShaperConfig::default();
+        assert_eq!(cell_width("a", &config), 1);
+        assert_eq!(cell_width("漢", &config), 2);
+        assert_eq!(cell_width("a漢b", &config), 4);
+
+        let mut shaper = CachingShaper::default();
+        assert_eq!(shaper.shape("a", FontStyle::empty())[0].cell_width, 1);
+        assert_eq!(shaper.shape("漢", FontStyle::empty())[0].cell_width, 2);
+    }
+
+    #[test]
+    fn test_shaped_run_metadata() {
+        let mut shaper = CachingShaper::default();
+        let (font_width, _) = shaper.font_base_dimensions();
+
+        let runs = shaper.shape_cached("abc", FontStyle::empty());
+        assert_eq!(runs.len(), 1);
+
+        let run = &runs[0];
+        assert!(!run.font_name.is_empty());
+        assert!(!run.used_fallback);
+        assert_eq!(run.cell_width, 3);
+        assert!((run.advance - 3.0 * font_width).abs() < 0.5);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_decoration_metrics_scale_with_size() {
+        let mut small_font = asset_skia_font(MISSING_GLYPH_FONT);
+        small_font.set_size(10.0);
+        let mut large_font = asset_skia_font(MISSING_GLYPH_FONT);
+        large_font.set_size(20.0);
+
+        let small = decoration_metrics(&small_font, 8.0);
+        let large = decoration_metrics(&large_font, 16.0);
+
+        let close = |a: f32, b: f32| (a - b).abs() < 0.01;
+        assert!(close(large.underline_y, small.underline_y * 2.0));
+        assert!(close(
+            large.underline_thickness,
+            small.underline_thickness * 2.0
+        ));
+        assert!(close(
+            large.undercurl_wavelength,
+            small.undercurl_wavelength * 2.0
+        ));
+        assert!(close(large.strikethrough_y, small.strikethrough_y * 2.0));
+    }
+
+    #[test]
+    fn test_missing_families_are_only_queried_once() {
+        let mut loader = FontLoader::new();
+
+        assert!(loader.load("Not A Real Font Family").is_none());
+        assert!(loader.load("Not A Real Font Family").is_none());
+        assert_eq!(loader.source_queries, 1);
+
+        loader.clear();
+        assert!(loader.load("Not A Real Font Family").is_none());
+        assert_eq!(loader.source_queries, 2);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_cold_styled_shaping() {
+        let start = std::time::Instant::now();
+        let mut shaper = CachingShaper::default();
+        let queries_after_setup = shaper.font_loader.source_queries;
+
+        for (index, character) in (b'a'..=b'z').enumerate() {
+            let text = (character as char).to_string().repeat(8);
+            let mut style = FontStyle::empty();
+            style.set(FontStyle::BOLD, index % 2 == 0);
+            style.set(FontStyle::ITALIC, index % 3 == 0);
+            shaper.shape_cached(&text, style);
+        }
+
+        log::debug!("Cold styled shaping took {:?}", start.elapsed());
+        assert_eq!(shaper.font_loader.source_queries, queries_after_setup);
+    }
+
+    #[test]
+    fn test_nearest_cell() {
+        assert_eq!(nearest_cell(7.6, 8.0, 3), 1);
+        assert_eq!(nearest_cell(-0.3, 8.0, 3), 0);
+        // Offsets past the covered cells stay in the last one
+        assert_eq!(nearest_cell(30.0, 8.0, 3), 2);
+    }
+
+    #[test]
+    fn test_subpixel_positions_are_not_snapped() {
+        let snapped_shaper = CachingShaper::default();
+        let subpixel_shaper = CachingShaper::new(
+            ShaperConfig {
+                subpixel: true,
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        let font_width = snapped_shaper.reference_advance().unwrap();
+
+        let snapped = &snapped_shaper.layout_runs("abc", false, false)[0];
+        for (index, glyph) in snapped.glyphs.iter().enumerate() {
+            assert!((glyph.position.x - index as f32 * font_width).abs() < std::f32::EPSILON);
+        }
+
+        let subpixel = &subpixel_shaper.layout_runs("abc", false, false)[0];
+        let style = TextStyle {
+            size: subpixel_shaper.options.size,
+        };
+        let layout = layout_run(&style, &subpixel.font, "abc");
+        for (glyph, laid_out) in subpixel.glyphs.iter().zip(layout.glyphs.iter()) {
+            assert!((glyph.position.x - laid_out.offset.x()).abs() < std::f32::EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_letter_spacing_widens_runs() {
+        for subpixel in [false, true].iter() {
+            let config = ShaperConfig {
+                subpixel: *subpixel,
+                ..ShaperConfig::default()
+            };
+            let spaced_config = ShaperConfig {
+                letter_spacing: 2.0,
+                ..config.clone()
+            };
+            let shaper = CachingShaper::new(config, CacheSettings::default());
+            let spaced_shaper = CachingShaper::new(spaced_config, CacheSettings::default());
+
+            let run = &shaper.layout_runs("abc", false, false)[0];
+            let spaced_run = &spaced_shaper.layout_runs("abc", false, false)[0];
+            let expected = run.advance + 2.0 * run.glyphs.len() as f32;
+            assert!((spaced_run.advance - expected).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_spacing_is_clamped() {
+        let config = ShaperConfig {
+            letter_spacing: -1000.0,
+            line_spacing: -1000.0,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        let (font_width, font_height) = shaper.font_base_dimensions();
+        assert!((font_width - 1.0).abs() < std::f32::EPSILON);
+        assert!((font_height - 1.0).abs() < std::f32::EPSILON);
+    }
+
+    #[test]
+    fn test_cell_metrics_are_memoized() {
+        let mut shaper = CachingShaper::default();
+
+        let first = shaper.cell_metrics();
+        let second = shaper.cell_metrics();
+        assert_eq!(first, second);
+        assert_eq!(shaper.stats.cell_metrics_misses, 1);
+        assert_eq!(shaper.stats.cell_metrics_hits, 1);
+
+        shaper.on_scale_change(2.0);
+        shaper.cell_metrics();
+        assert_eq!(shaper.stats.cell_metrics_misses, 2);
+    }
+
+    #[test]
+    #[ignore = "needs Symbols Nerd Font Mono installed"]
+    fn test_nerd_font_symbols_resolve() {
+        let mut shaper = CachingShaper::default();
+        assert!(shaper.font_loader.load(NERD_SYMBOL_FONT).is_some());
+
+        let runs = shaper.layout_runs("\u{f115}", false, false);
+        assert_eq!(runs.len(), 1);
+        assert!(font_has_glyph(&runs[0].font.font, '\u{f115}'));
+        assert_eq!(runs[0].cell_width, 1);
+    }
+
+    #[test]
+    fn test_split_box_drawing() {
+        use super::LayoutSegment::*;
+
+        assert_eq!(
+            split_box_drawing(Text("a\u{2500}\u{2550}b")),
+            vec![Text("a"), BoxDrawing("\u{2500}\u{2550}"), Text("b")]
+        );
+        assert_eq!(
+            split_box_drawing(Text("\u{2503}")),
+            vec![BoxDrawing("\u{2503}")]
+        );
+        assert_eq!(
+            split_box_drawing(Cluster("e\u{301}")),
+            vec![Cluster("e\u{301}")]
+        );
+    }
+
+    #[test]
+    fn test_box_drawing_fills_cells() {
+        let config = ShaperConfig {
+            subpixel: true,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        let (font_width, _) = shaper.font_base_dimensions();
+
+        let runs = shaper.shape("\u{2554}", FontStyle::empty());
+        assert_eq!(runs.len(), 1);
+        assert!((runs[0].advance - font_width).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_bidi_runs() {
+        assert_eq!(bidi_runs("abc"), vec![("abc", false)]);
+        assert_eq!(
+            bidi_runs("ab \u{5e9}\u{5dc}\u{5d5}\u{5dd} cd"),
+            vec![
+                ("ab ", false),
+                ("\u{5e9}\u{5dc}\u{5d5}\u{5dd}", true),
+                (" cd", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hebrew_is_reversed() {
+        use super::LayoutSegment::*;
+
+        assert_eq!(
+            visual_segments("ab \u{5e9}\u{5dc}\u{5d5}\u{5dd} cd", true),
+            vec![
+                Text("ab "),
+                RightToLeft("\u{5e9}\u{5dc}\u{5d5}\u{5dd}"),
+                Text(" cd")
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a system font with Hebrew letters, such as DejaVu Sans"]
+    fn test_hebrew_is_laid_out_right_to_left() {
+        // The whole run is laid out at once and the first letter ends up in the rightmost cell
+        let shaper = CachingShaper::default();
+        let runs = shaper.layout_runs("\u{5e9}\u{5dc}\u{5d5}\u{5dd}", false, false);
+        let glyphs: Vec<&ShapedGlyph> = runs.iter().flat_map(|run| run.glyphs.iter()).collect();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(glyphs.len(), 4);
+        assert!(glyphs
+            .windows(2)
+            .all(|pair| pair[0].position.x > pair[1].position.x));
+    }
+
+    #[test]
+    fn test_missing_glyphs_are_reported() {
+        log::set_logger(&LOGGER).ok();
+        log::set_max_level(log::LevelFilter::Trace);
+        let mut shaper = CachingShaper::default();
+
+        let runs = shaper.shape("\u{10fffd}", FontStyle::empty());
+        assert!(runs.iter().any(|run| run.has_missing_glyphs));
+        shaper.shape("\u{10fffd}", FontStyle::empty());
+        assert_eq!(shaper.missing_codepoints, vec!['\u{10fffd}']);
+
+        let runs = shaper.shape("a b", FontStyle::empty());
+        assert!(runs.iter().all(|run| !run.has_missing_glyphs));
+        assert_eq!(shaper.missing_codepoints, vec!['\u{10fffd}']);
+
+        let logs = CAPTURED_LOGS.lock();
+        assert!(logs
+            .iter()
+            .any(|line| line.starts_with("No font has a glyph for U+10FFFD")));
+    }
+
+    #[test]
+    fn test_font_style_keys() {
+        let mut shaper = CachingShaper::default();
+
+        // Decorations are drawn over the shaped text, so undercurled text reuses the bold runs
+        shaper.shape_cached("a", FontStyle::BOLD);
+        shaper.shape_cached("a", FontStyle::BOLD | FontStyle::UNDERCURL);
+        assert_eq!(shaper.stats.blob_hits, 1);
+        assert_eq!(shaper.stats.blob_misses, 1);
+
+        // Italic text picks another face and reversed runs are drawn in another color
+        shaper.shape_cached("a", FontStyle::BOLD | FontStyle::ITALIC);
+        shaper.shape_cached("a", FontStyle::BOLD | FontStyle::REVERSE);
+        assert_eq!(shaper.stats.blob_hits, 1);
+        assert_eq!(shaper.stats.blob_misses, 3);
+
+        let reversed = shaper.shape_cached("a", FontStyle::BOLD | FontStyle::REVERSE);
+        assert!(reversed[0].reverse);
+        assert!(!shaper.shape_cached("a", FontStyle::BOLD)[0].reverse);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_fonts_load_from_file_paths() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/fonts/Extra Symbols.otf"
+        );
+        let mut shaper = CachingShaper::default();
+
+        assert!(shaper.update_font(&format!("file://{}:h14", path)));
+        assert_eq!(
+            shaper.options.fallback_list,
+            vec![format!("file://{}", path)]
+        );
+
+        let font_name = asset_skribo_font(EXTRA_SYMBOL_FONT).font.full_name();
+        assert!(shaper.font_set.primary_font_names.contains(&font_name));
+        assert!(!shaper.shape("a", FontStyle::empty()).is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a system family with a bold face, DejaVu Sans, Arial or Helvetica"]
+    fn test_weight_picks_the_closest_face() {
+        let guifont = format!("{}:h14", SECOND_TEST_FONT);
+        let mut regular_shaper = CachingShaper::default();
+        regular_shaper.update_font(&guifont);
+        let mut heavy_shaper = CachingShaper::new(
+            ShaperConfig {
+                weight: Some(700),
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        heavy_shaper.update_font(&guifont);
+
+        let font_name = |shaper: &CachingShaper, bold| {
+            shaper.layout_runs("a", bold, false)[0]
+                .font
+                .font
+                .full_name()
+        };
+        let bold_font_name = font_name(&regular_shaper, true);
+        assert_ne!(font_name(&regular_shaper, false), bold_font_name);
+        assert_eq!(font_name(&heavy_shaper, false), bold_font_name);
+
+        // The face is picked before layout, so the glyphs get drawn from it as well
+        let runs = heavy_shaper.shape("a", FontStyle::empty());
+        assert_eq!(runs[0].font_name, bold_font_name);
+    }
+
+    #[test]
+    fn test_mixed_advance_positions() {
+        let shaper = CachingShaper::default();
+        let font_width = shaper.reference_advance().unwrap();
+
+        let positions: Vec<f32> = shaper
+            .layout_runs("a漢b", false, false)
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.position.x))
+            .collect();
+        let expected = [0.0, font_width, 3.0 * font_width];
+
+        assert_eq!(positions.len(), expected.len());
+        for (position, expected) in positions.iter().zip(expected.iter()) {
+            assert!((position - expected).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_measurement_string_advances() {
+        let config = ShaperConfig {
+            measurement_string: String::from("MMMM"),
+            ..ShaperConfig::default()
+        };
+        let shaper = CachingShaper::new(config, CacheSettings::default());
+        let font_width = shaper.reference_advance().unwrap();
+
+        let advances = shaper.measurement_advances();
+        assert_eq!(advances.len(), 3);
+        assert!(advances_are_uniform(&advances));
+        assert!((most_common_advance(&advances).unwrap() - font_width).abs() < 0.001);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_guifont_fallback_rules_pin_ranges() {
+        let pinned_font = asset_skribo_font(EXTRA_SYMBOL_FONT);
+        assert!(font_has_glyph(&pinned_font.font, '\u{25ca}'));
+
+        let mut shaper = CachingShaper::default();
+        let unpinned_runs = shaper.layout_runs("a\u{25ca}", false, false);
+        let unpinned_font_name = unpinned_runs.last().unwrap().font.font.full_name();
+
+        let generation = shaper.font_generation;
+        shaper.apply_guifont(&format!(
+            "{}:h14:pin=25CA={}:pin=zz=Nowhere",
+            SYSTEM_DEFAULT_FONT, EXTRA_SYMBOL_FONT
+        ));
+        assert_eq!(
+            shaper.options.fallback_rules,
+            vec![FallbackRule {
+                range: 0x25ca..=0x25ca,
+                family: String::from(EXTRA_SYMBOL_FONT),
+            }]
+        );
+        assert!(shaper.font_generation > generation);
+
+        let runs = shaper.layout_runs("a\u{25ca}", false, false);
+        let pinned_run = runs.last().unwrap();
+        assert_eq!(
+            pinned_run.font.font.full_name(),
+            pinned_font.font.full_name()
+        );
+        assert_ne!(unpinned_font_name, pinned_font.font.full_name());
+        assert_eq!(
+            runs[0].font.font.full_name(),
+            unpinned_runs[0].font.font.full_name()
+        );
+    }
+
+    #[test]
+    fn test_font_height_is_whole_device_pixels() {
+        let mut shaper = CachingShaper::default();
+
+        let (_, font_height) = shaper.on_scale_change(2.0);
+        let device_height = font_height * 2.0;
+        assert!((device_height - device_height.round()).abs() < 0.001);
+
+        let (_, unsnapped_height) = shaper.cell_metrics();
+        assert!(device_height >= unsnapped_height);
+    }
+
+    #[test]
+    fn test_italic_angle_from_post_table() {
+        let angle = (-12.5f32 * 65536.0) as i32;
+        let mut table = vec![0, 3, 0, 0];
+        table.extend_from_slice(&angle.to_be_bytes());
+
+        let italic_angle = italic_angle_from_post_table(&table).unwrap();
+        assert!((italic_angle + 12.5).abs() < 0.001);
+        assert!(((-12.5f32).to_radians().tan() - italic_angle.to_radians().tan()).abs() < 0.001);
+        assert!(italic_angle_from_post_table(&table[..6]).is_none());
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_upright_fonts_use_default_italic_angle() {
+        let font = asset_skribo_font(MISSING_GLYPH_FONT);
+        let expected = DEFAULT_ITALIC_ANGLE.to_radians().tan();
+        assert!((synthetic_italic_skew(&font.font) - expected).abs() < 0.001);
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with U+2602, such as Noto Color Emoji"]
+    fn test_presentation_selectors_pick_fonts() {
+        let shaper = CachingShaper::default();
+        let emoji_font = shaper
+            .font_set
+            .emoji_fonts
+            .iter()
+            .find(|font| font_has_glyph(&font.font, '\u{2602}'))
+            .unwrap()
+            .font
+            .full_name();
+
+        let emoji_runs = shaper.layout_runs("\u{2602}\u{fe0f}", false, false);
+        assert_eq!(emoji_runs.len(), 1);
+        assert_eq!(emoji_runs[0].font.font.full_name(), emoji_font);
+        assert_eq!(emoji_runs[0].cell_width, 1);
+
+        let text_runs = shaper.layout_runs("\u{2602}\u{fe0e}", false, false);
+        assert_eq!(text_runs.len(), 1);
+        assert_ne!(text_runs[0].font.font.full_name(), emoji_font);
+    }
+
+    #[test]
+    fn test_blob_cache_respects_byte_budget() {
+        let byte_budget = 4096;
+        let cache_settings = CacheSettings {
+            blob_byte_budget: Some(byte_budget),
+            ..CacheSettings::default()
+        };
+        let mut shaper = CachingShaper::new(ShaperConfig::default(), cache_settings);
+
+        let huge = "x".repeat(300);
+        shaper.shape_cached(&huge, FontStyle::empty());
+        for character in (b'a'..=b'z').map(char::from) {
+            shaper.shape_cached(&character.to_string(), FontStyle::empty());
+            assert!(shaper.blob_cache.bytes <= byte_budget);
+        }
+
+        assert!(!shaper
+            .blob_cache
+            .contains(&shaper.shape_key(&huge, FontStyle::empty())));
+        assert!(shaper
+            .blob_cache
+            .contains(&shaper.shape_key("z", FontStyle::empty())));
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    fn covered_pixels(blob: &TextBlob, paint: &Paint) -> usize {
+        let (width, height) = (64, 32);
+        let mut surface = Surface::new_raster_n32_premul((width, height)).unwrap();
+        surface.canvas().draw_text_blob(blob, (0, 20), paint);
+
+        let info = ImageInfo::new_n32_premul((width, height), None);
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        assert!(surface.read_pixels(&info, &mut pixels, (width * 4) as usize, (0, 0)));
+        pixels.chunks(4).filter(|pixel| pixel[3] != 0).count()
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_synthetic_bold_strength_changes_coverage() {
+        let skia_font = asset_skia_font(MISSING_GLYPH_FONT);
+        let blob = make_blob(&skia_font, &[ShapedGlyph::new(1, Point::new(4.0, 0.0))]).unwrap();
+
+        let coverage = |strength: f32| {
+            let mut paint = Paint::default();
+            paint.set_style(PaintStyle::StrokeAndFill);
+            paint.set_stroke_width(synthetic_bold_stroke_width(strength, DEFAULT_FONT_SIZE));
+            covered_pixels(&blob, &paint)
+        };
+
+        assert!(coverage(2.0) > coverage(0.5));
+        assert!(
+            (synthetic_bold_stroke_width(0.5, DEFAULT_FONT_SIZE * 2.0) - 1.0).abs()
+                < std::f32::EPSILON
+        );
+    }
+
+    #[test]
+    fn test_empty_text_shapes_no_runs() {
+        let mut shaper = CachingShaper::default();
+        assert!(shaper.shape("", FontStyle::empty()).is_empty());
+        assert!(shaper.shape_cached("", FontStyle::BOLD).is_empty());
+    }
+
+    #[test]
+    fn test_edging_and_hinting_are_applied() {
+        let config = ShaperConfig {
+            edging: Edging::Alias,
+            hinting: FontHinting::None,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        let font = shaper.layout_runs("a", false, false)[0].font.clone();
+
+        let skia_font = shaper.get_skia_font(&font, false, false).unwrap();
+        assert_eq!(skia_font.edging(), Edging::Alias);
+        assert_eq!(skia_font.hinting(), FontHinting::None);
+    }
+
+    #[test]
+    fn test_text_shaper_uses_cache() {
+        let mut shaper = CachingShaper::default();
+        let text_shaper: &mut dyn TextShaper = &mut shaper;
+
+        text_shaper.shape_line(&[], "abc", FontStyle::empty(), 0);
+        text_shaper.shape_line(&[], "abc", FontStyle::empty(), 0);
+        assert!(text_shaper.cell_metrics().0 > 0.0);
+
+        assert_eq!(shaper.stats.blob_misses, 1);
+        assert_eq!(shaper.stats.blob_hits, 1);
+    }
+
+    #[test]
+    fn test_skia_fonts_are_cached_per_key() {
+        let mut shaper = CachingShaper::default();
+        let font = shaper.layout_runs("a", false, false)[0].font.clone();
+
+        let first = shaper.get_skia_font(&font, false, false).unwrap() as *const SkiaFont;
+        let second = shaper.get_skia_font(&font, false, false).unwrap() as *const SkiaFont;
+        assert_eq!(first, second);
+        assert_eq!(shaper.stats.font_misses, 1);
+        assert_eq!(shaper.stats.font_hits, 1);
+
+        TextShaper::clear(&mut shaper);
+        assert!(shaper.font_cache.is_empty());
+    }
+
+    #[test]
+    fn test_blend_is_kept_through_the_cache() {
+        let mut shaper = CachingShaper::default();
+
+        shaper.shape_cached_blended("abc", FontStyle::empty(), 50);
+        let runs = shaper.shape_cached_blended("abc", FontStyle::empty(), 50);
+        assert!(!runs.is_empty());
+        assert!(runs.iter().all(|run| run.blend == 50));
+        assert_eq!(shaper.stats.blob_hits, 1);
+
+        let opaque_runs = shaper.shape_cached("abc", FontStyle::empty());
+        assert!(opaque_runs.iter().all(|run| run.blend == 0));
+
+        assert_eq!(runs[0].blended_alpha(255), 127);
+        assert_eq!(opaque_runs[0].blended_alpha(255), 255);
+    }
+
+    #[test]
+    fn test_tabs_expand_to_tab_stops() {
+        let tab_stops = |tab_width| ShaperConfig {
+            tab_width,
+            ..ShaperConfig::default()
+        };
+        assert_eq!(expand_tabs("a\tb", &tab_stops(4)), "a   b");
+        assert_eq!(expand_tabs("\u{4e00}\ta", &tab_stops(4)), "\u{4e00}  a");
+        assert_eq!(expand_tabs("ab\t\t", &tab_stops(2)), "ab    ");
+
+        let config = ShaperConfig {
+            tab_width: 4,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        let (font_width, _) = shaper.font_base_dimensions();
+        let width: f32 = shaper
+            .shape("\t", FontStyle::empty())
+            .iter()
+            .map(|run| run.advance)
+            .sum();
+        assert!((width - 4.0 * font_width).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_missing_codepoints_can_be_drawn_as_hex() {
+        let mut shaper = CachingShaper::default();
+        let runs = shaper.shape("\u{10fffd}", FontStyle::empty());
+        assert!(runs.iter().all(|run| run.codepoint_boxes.is_empty()));
+
+        let config = ShaperConfig {
+            draw_missing_codepoint: true,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        let (_, font_height) = shaper.cell_metrics();
+        let runs = shaper.shape("\u{10fffd}", FontStyle::empty());
+
+        let run = runs
+            .iter()
+            .find(|run| !run.codepoint_boxes.is_empty())
+            .unwrap();
+        assert_eq!(run.codepoint_boxes.len(), 1);
+        assert!(run.codepoint_boxes[0].height() < font_height);
+        assert_eq!(run.glyph_count, "10FFFD".len());
+        assert!(!run.blob.as_ref().unwrap().bounds().is_empty());
+    }
+
+    #[test]
+    fn test_emoji_family_can_be_disabled() {
+        let config = ShaperConfig {
+            emoji_family: None,
+            ..ShaperConfig::default()
+        };
+        let shaper = CachingShaper::new(config, CacheSettings::default());
+        assert!(shaper.font_set.emoji_fonts.is_empty());
+
+        for run in shaper.layout_runs("\u{1f600}", false, false) {
+            assert_ne!(run.font.font.family_name(), SYSTEM_EMOJI_FONT);
+        }
+    }
+
+    #[test]
+    fn test_vertical_metrics_span_the_cell() {
+        let mut shaper = CachingShaper::default();
+        let (_, font_height) = shaper.cell_metrics();
+
+        let vertical_metrics = shaper.vertical_metrics();
+        assert!((vertical_metrics.ascent - vertical_metrics.descent - font_height).abs() < 0.01);
+        assert!(vertical_metrics.descent <= 0.0);
+        assert_eq!(shaper.vertical_metrics(), vertical_metrics);
+    }
+
+    #[test]
+    fn test_emoji_runs_are_flagged_color() {
+        let mut shaper = CachingShaper::default();
+        let runs = shaper.shape("abc", FontStyle::empty());
+        assert!(runs.iter().all(|run| !run.is_color));
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with U+1F600, such as Noto Color Emoji"]
+    fn test_emoji_font_runs_are_flagged_color() {
+        let mut shaper = CachingShaper::default();
+        let emoji_font_names: Vec<String> = shaper
+            .font_set
+            .emoji_fonts
+            .iter()
+            .map(|font| font.font.full_name())
+            .collect();
+        let runs = shaper.shape("\u{1f600}", FontStyle::empty());
+        assert_eq!(runs.len(), 1);
+        assert!(emoji_font_names.contains(&runs[0].font_name));
+        assert!(runs[0].is_color);
+    }
+
+    #[test]
+    fn test_apply_guifont_parses_escaped_spaces() {
+        let mut shaper = CachingShaper::default();
+
+        let (font_width, font_height) = shaper.apply_guifont("FiraCode\\ Nerd\\ Font:h14").unwrap();
+        assert_eq!(
+            shaper.options.fallback_list,
+            vec![String::from("FiraCode Nerd Font")]
+        );
+        assert!((shaper.options.size - 14.0).abs() < std::f32::EPSILON);
+        assert!(font_width > 0.0);
+        assert!(font_height > 0.0);
+        assert!(shaper.apply_guifont("FiraCode\\ Nerd\\ Font:h14").is_none());
+    }
+
+    #[test]
+    fn test_emoji_sequences_stay_together() {
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let flag = "\u{1f1e9}\u{1f1ea}";
+        assert!(is_emoji_sequence(family));
+        assert!(is_emoji_sequence(flag));
+        assert!(!is_emoji_sequence("\u{1f1e9}"));
+        assert!(!is_emoji_sequence("e\u{301}"));
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with the family sequence, such as Noto Color Emoji"]
+    fn test_emoji_sequences_are_laid_out_together() {
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let shaper = CachingShaper::default();
+        assert!(shaper.font_set.emoji_fonts.iter().any(|font| {
+            family.chars().all(|character| {
+                character == ZERO_WIDTH_JOINER || font_has_glyph(&font.font, character)
+            })
+        }));
+
+        let runs = shaper.layout_runs(family, false, false);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].cell_width, 2);
+        assert!(shaper.font_set.is_emoji_font(&runs[0].font));
+    }
+
+    #[test]
+    fn test_most_common_advance_ties_are_stable() {
+        for _ in 0..20 {
+            let advances = [9.0, 8.0, 9.0, 8.0, 12.0];
+            assert!((most_common_advance(&advances).unwrap() - 8.0).abs() < std::f32::EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_single_ascii_fast_path_matches_layout() {
+        let shaper = CachingShaper::default();
+
+        for (bold, italic) in [(false, false), (true, false), (false, true)].iter() {
+            let fast_run = shaper
+                .single_ascii_run(&shaper.font_set, "a", *bold, *italic)
+                .unwrap();
+            let full_runs = shaper.layout_runs_with_skribo(&shaper.font_set, "a", *bold, *italic);
+            assert_eq!(full_runs.len(), 1);
+            assert_eq!(fast_run.glyphs, full_runs[0].glyphs);
+            assert!((fast_run.advance - full_runs[0].advance).abs() < std::f32::EPSILON);
+            assert_eq!(
+                fast_run.font.font.full_name(),
+                full_runs[0].font.font.full_name()
+            );
+        }
+
+        assert!(shaper
+            .single_ascii_run(&shaper.font_set, "ab", false, false)
+            .is_none());
+        assert!(shaper
+            .single_ascii_run(&shaper.font_set, "\u{e9}", false, false)
+            .is_none());
+
+        let mut width_overrides = HashMap::new();
+        width_overrides.insert('a', 2);
+        let wide_shaper = CachingShaper::new(
+            ShaperConfig {
+                width_overrides,
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        assert!(wide_shaper
+            .single_ascii_run(&wide_shaper.font_set, "a", false, false)
+            .is_none());
+        let runs = wide_shaper.layout_runs("a", false, false);
+        assert_eq!(runs.iter().map(|run| run.cell_width).sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn test_skin_tone_modifiers_join_their_emoji() {
+        let thumbs_up = "\u{1f44d}\u{1f3fd}";
+        assert!(is_emoji_sequence(thumbs_up));
+        assert_eq!(layout_segments(thumbs_up, true).len(), 1);
+        assert_eq!(cell_width(thumbs_up, &ShaperConfig::default()), 2);
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with skin tone modifiers, such as Noto Color Emoji"]
+    fn test_skin_tone_modifiers_are_laid_out_with_their_emoji() {
+        let thumbs_up = "\u{1f44d}\u{1f3fd}";
+        let shaper = CachingShaper::default();
+        assert!(shaper.font_set.emoji_fonts.iter().any(|font| {
+            thumbs_up
+                .chars()
+                .all(|character| font_has_glyph(&font.font, character))
+        }));
+
+        let runs = shaper.layout_runs(thumbs_up, false, false);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].cell_width, 2);
+    }
+
+    #[test]
+    fn test_preload_fills_font_cache() {
+        let mut shaper = CachingShaper::default();
+        shaper.preload();
+        let font_misses = shaper.stats.font_misses;
+        shaper.preload();
+        assert_eq!(shaper.stats.font_misses, font_misses);
+        assert_eq!(shaper.stats.cell_metrics_misses, 1);
+
+        shaper.stats = CacheStats::default();
+        shaper.shape("a", FontStyle::empty());
+        assert_eq!(shaper.stats.font_misses, 0);
+        assert_eq!(shaper.stats.font_hits, 1);
+    }
+
+    #[test]
+    fn test_closest_face_matches_style_and_weight() {
+        let face = |weight, style| Properties {
+            weight,
+            style,
+            stretch: Stretch::NORMAL,
+        };
+        let bold = face(Weight::BOLD, Style::Normal);
+        let italic = face(Weight::NORMAL, Style::Italic);
+        let regular = face(Weight::NORMAL, Style::Normal);
+        let bold_italic = face(Weight::BOLD, Style::Italic);
+
+        let family = [bold, italic, regular, bold_italic];
+        assert_eq!(closest_face(&family, regular), Some(2));
+        assert_eq!(closest_face(&family, bold), Some(0));
+        assert_eq!(closest_face(&family, italic), Some(1));
+        assert_eq!(closest_face(&family, bold_italic), Some(3));
+
+        let oblique = face(Weight::NORMAL, Style::Oblique);
+        assert_eq!(closest_face(&[bold, oblique], italic), Some(1));
+        assert_eq!(closest_face(&[bold, regular], italic), Some(1));
+        assert_eq!(closest_face(&[], regular), None);
+    }
+
+    #[test]
+    fn test_single_cell_matches_line() {
+        let mut shaper = CachingShaper::default();
+        let (font_width, _) = shaper.cell_metrics();
+
+        let cell = shaper.shape_single_cell("bcd", FontStyle::empty()).unwrap();
+        assert_eq!(cell.glyph_count, 1);
+        assert_eq!(cell.cell_width, 1);
+        assert!(shaper
+            .blob_cache
+            .contains(&shaper.shape_key("b", FontStyle::empty())));
+
+        let line_glyphs = &shaper.layout_runs("abc", false, false)[0].glyphs;
+        let cell_glyphs = &shaper.layout_runs("b", false, false)[0].glyphs;
+        assert_eq!(line_glyphs[1].glyph_id, cell_glyphs[0].glyph_id);
+        assert!((line_glyphs[1].position.x - cell_glyphs[0].position.x - font_width).abs() < 0.001);
+        assert!(shaper.shape_single_cell("", FontStyle::empty()).is_none());
+    }
+
+    #[test]
+    fn test_stylistic_sets_are_validated() {
+        assert_eq!(stylistic_set_number("ss01"), Some(1));
+        assert_eq!(stylistic_set_number("ss20"), Some(20));
+        assert_eq!(stylistic_set_number("ss00"), None);
+        assert_eq!(stylistic_set_number("ss21"), None);
+        assert_eq!(stylistic_set_number("ss+1"), None);
+        assert_eq!(stylistic_set_number("ss1"), None);
+        assert_eq!(stylistic_set_number("liga"), None);
+
+        let config = ShaperConfig {
+            features: vec![
+                (String::from("ss02"), 1),
+                (String::from("ss01"), 1),
+                (String::from("ss03"), 1),
+                (String::from("ss03"), 0),
+                (String::from("ss99"), 1),
+            ],
+            ..ShaperConfig::default()
+        };
+        assert_eq!(config.stylistic_sets(&[]), vec![1, 2]);
+        assert_eq!(
+            config.stylistic_sets(&[(String::from("ss01"), 0), (String::from("ss04"), 1)]),
+            vec![2, 4]
+        );
+    }
+
+    #[test]
+    fn test_stylistic_sets_substitute_glyphs() {
+        // ss01 turns glyph 5 into 9 with a delta, liga turns glyph 7 into 30 from a list
+        let words: [u16; 42] = [
+            1, 0, 0, 10, 36, // header
+            2, 0x7373, 0x3031, 14, 0x6c69, 0x6761, 20, // feature list
+            0, 1, 0, // ss01
+            0, 1, 1, // liga
+            2, 6, 26, // lookup list
+            1, 0, 1, 8, 1, 6, 4, 1, 1, 5, // single substitution with a delta
+            1, 0, 1, 8, 2, 8, 1, 30, 1, 1, 7, // single substitution from a list
+        ];
+        let gsub: Vec<u8> = words
+            .iter()
+            .flat_map(|word| word.to_be_bytes().to_vec())
+            .collect();
+
+        let substitutions = stylistic_substitutions(&gsub, &[1]);
+        assert_eq!(
+            substitutions,
+            vec![(5, 9)].into_iter().collect::<HashMap<u16, u16>>()
+        );
+        assert!(stylistic_substitutions(&gsub, &[2]).is_empty());
+        assert!(stylistic_substitutions(&gsub[..40], &[1]).is_empty());
+
+        let mut glyphs = vec![
+            ShapedGlyph::new(5, Point::new(0.0, 0.0)),
+            ShapedGlyph::new(6, Point::new(8.0, 0.0)),
+        ];
+        substitute_glyphs(&mut glyphs, &substitutions);
+        assert_eq!(glyphs[0].glyph_id, 9);
+        assert_eq!(glyphs[1].glyph_id, 6);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_stylistic_substitutions_are_cached_per_set() {
+        let mut shaper = CachingShaper::default();
+        let font = asset_skribo_font(EXTRA_SYMBOL_FONT).font;
+
+        shaper.font_stylistic_substitutions(&font, &[1]);
+        shaper.font_stylistic_substitutions(&font, &[1, 2]);
+        shaper.font_stylistic_substitutions(&font, &[1]);
+        assert_eq!(shaper.stylistic_substitutions.len(), 2);
+        assert!(shaper
+            .stylistic_substitutions
+            .contains_key(&(font.full_name(), vec![1, 2])));
+    }
+
+    #[test]
+    fn test_keycaps_are_emoji_sequences() {
+        let keycap = "1\u{fe0f}\u{20e3}";
+        assert!(is_emoji_sequence(keycap));
+        assert!(is_emoji_sequence("#\u{20e3}"));
+        assert!(!is_emoji_sequence("a\u{20e3}"));
+        assert_eq!(layout_segments(keycap, true).len(), 1);
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with keycaps, such as Noto Color Emoji"]
+    fn test_keycaps_are_laid_out_in_the_emoji_font() {
+        let keycap = "1\u{fe0f}\u{20e3}";
+        let shaper = CachingShaper::default();
+        assert!(shaper.font_set.emoji_fonts.iter().any(|font| {
+            keycap
+                .chars()
+                .all(|character| is_invisible(character) || font_has_glyph(&font.font, character))
+        }));
+
+        let runs = shaper.layout_runs(keycap, false, false);
+        assert_eq!(runs.len(), 1);
+        assert!(shaper.font_set.is_emoji_font(&runs[0].font));
+    }
+
+    #[test]
+    fn test_scale_change_drops_blobs_only() {
+        let mut shaper = CachingShaper::default();
+        let advance = |runs: &[ShapedRun]| runs.iter().map(|run| run.advance).sum::<f32>();
+        let (font_width, _) = shaper.font_base_dimensions();
+        let (device_width, device_height) = shaper.cell_metrics();
+        let unscaled_advance = advance(&shaper.shape_cached("abc", FontStyle::empty()));
+
+        let (scaled_width, font_height) = shaper.on_scale_change(1.5);
+        assert!(((font_height * 1.5) - (font_height * 1.5).round()).abs() < 0.001);
+        assert!((scaled_width - font_width).abs() < 0.01);
+        assert!(shaper.blob_cache.entries.is_empty());
+        assert!(!shaper.font_cache.is_empty());
+
+        // Text is shaped in device pixels from then on
+        let (scaled_device_width, scaled_device_height) = shaper.cell_metrics();
+        assert!((scaled_device_width - device_width * 1.5).abs() < 0.01);
+        assert!((scaled_device_height - device_height * 1.5).abs() < 0.01);
+        let scaled_advance = advance(&shaper.shape_cached("abc", FontStyle::empty()));
+        assert!((scaled_advance - unscaled_advance * 1.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_fractional_scales_key_separately() {
+        let mut shaper = CachingShaper::default();
+        let font = shaper.layout_runs("a", false, false)[0].font.clone();
+
+        let mut keys = Vec::new();
+        let mut sizes = Vec::new();
+        for scale in [1.25, 1.5].iter() {
+            shaper.on_scale_change(*scale);
+            keys.push(shaper.shape_key("a", FontStyle::empty()));
+            sizes.push(shaper.get_skia_font(&font, false, false).unwrap().size());
+        }
+
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[0].size, keys[1].size);
+        assert_eq!(shaper.font_cache.len(), 2);
+        assert!((sizes[0] - DEFAULT_FONT_SIZE * 1.25).abs() < std::f32::EPSILON);
+        assert!((sizes[1] - DEFAULT_FONT_SIZE * 1.5).abs() < std::f32::EPSILON);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_fallback_chain_lists_primary_fonts_first() {
+        // A bundled font stands in for the emoji family, so there is one whatever is installed
+        let config = ShaperConfig {
+            emoji_family: Some(String::from(MISSING_GLYPH_FONT)),
+            ..ShaperConfig::default()
+        };
+        let shaper = CachingShaper::new(config, CacheSettings::default());
+        let chain = shaper.fallback_chain(FontStyle::empty());
+
+        assert!(shaper.font_set.primary_font_names.contains(&chain[0]));
+        let emoji_font_name = asset_skribo_font(MISSING_GLYPH_FONT).font.full_name();
+        let emoji_position = chain.iter().position(|name| *name == emoji_font_name);
+        assert!(emoji_position.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_line_chunks() {
+        assert_eq!(line_chunks("let a  = b;"), vec!["let ", "a  ", "= ", "b;"]);
+        assert_eq!(line_chunks("  a"), vec!["  ", "a"]);
+        assert_eq!(line_chunks("a \u{301}b"), vec!["a \u{301}b"]);
+        assert_eq!(line_chunks("a\tb c"), vec!["a\tb c"]);
+        assert!(line_chunks("").is_empty());
+    }
+
+    #[test]
+    fn test_incremental_line_shaping_reuses_chunks() {
+        let mut shaper = CachingShaper::default();
+        let words: Vec<String> = (0..20).map(|index| format!("word{}", index)).collect();
+        let line = words.join(" ");
+        let shaped = shaper.shape_line_incremental(&[], &line, FontStyle::empty(), 0);
+        assert_eq!(shaped.len(), 20);
+        assert_eq!(shaper.chunks_shaped, 20);
+        assert_eq!(shaper.stats.blob_misses, 20);
+
+        let edited_line = line.replace("word10", "word1x0");
+        let edited = shaper.shape_line_incremental(&shaped, &edited_line, FontStyle::empty(), 0);
+        assert_eq!(shaper.chunks_shaped, 21);
+        assert_eq!(shaper.stats.blob_misses, 21);
+
+        let fresh = CachingShaper::default().shape_line_incremental(
+            &[],
+            &edited_line,
+            FontStyle::empty(),
+            0,
+        );
+        for (edited_chunk, fresh_chunk) in edited.iter().zip(fresh.iter()) {
+            assert_eq!(edited_chunk.text, fresh_chunk.text);
+            assert_eq!(edited_chunk.cell_offset, fresh_chunk.cell_offset);
+            assert_eq!(edited_chunk.runs.len(), fresh_chunk.runs.len());
+        }
+        assert_eq!(edited[19].cell_offset, shaped[19].cell_offset + 1);
+    }
+
+    #[test]
+    fn test_malformed_font_data_is_rejected() {
+        assert!(skia_typeface(b"").is_none());
+        assert!(skia_typeface(b"OTTO this is not a font").is_none());
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_fonts_skia_can_load_are_kept() {
+        let font_data = Asset::get(MISSING_GLYPH_FONT).unwrap();
+        assert!(skia_typeface(&font_data).is_some());
+
+        let mut family = ExtendedFontFamily::new();
+        family.add_font(asset_skribo_font(MISSING_GLYPH_FONT));
+        assert_eq!(family.fonts.len(), 1);
+    }
+
+    #[test]
+    fn test_whitespace_is_not_laid_out() {
+        let mut shaper = CachingShaper::new(
+            ShaperConfig {
+                letter_spacing: 1.5,
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        let cell_advance = shaper.cell_advance().unwrap();
+        let (font_width, _) = shaper.cell_metrics();
+        assert!((cell_advance - font_width).abs() < std::f32::EPSILON);
+
+        let runs = shaper.shape(&" ".repeat(50), FontStyle::empty());
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].blob.is_none());
+        assert_eq!(runs[0].glyph_count, 0);
+        assert_eq!(runs[0].cell_width, 50);
+        assert!((runs[0].advance - 50.0 * cell_advance).abs() < 0.001);
+
+        // Trailing whitespace is shaped with the text, and spaced out like it
+        let runs = shaper.shape("ab  ", FontStyle::empty());
+        assert!(runs[0].blob.is_some());
+        let advance: f32 = runs.iter().map(|run| run.advance).sum();
+        assert!((advance - 4.0 * cell_advance).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_guifont_features_are_parsed() {
+        let mut shaper = CachingShaper::default();
+        assert!(shaper.ligatures());
+
+        shaper.apply_guifont("Fira\\ Code:h13:feat=calt=0,liga=0,ss01,kern=x");
+        assert_eq!(
+            shaper.options.fallback_list,
+            vec![String::from("Fira Code")]
+        );
+        assert!((shaper.options.size - 13.0).abs() < std::f32::EPSILON);
+        assert_eq!(
+            shaper.options.features,
+            vec![
+                (String::from("calt"), 0),
+                (String::from("liga"), 0),
+                (String::from("ss01"), 1)
+            ]
+        );
+        assert!(!shaper.ligatures());
+        assert_eq!(
+            shaper.config.stylistic_sets(&shaper.options.features),
+            vec![1]
+        );
+
+        shaper.apply_guifont("Fira\\ Code:h13");
+        assert!(shaper.options.features.is_empty());
+        assert!(shaper.ligatures());
+    }
+
+    #[test]
+    fn test_cell_width_matches_shaping() {
+        let mut shaper = CachingShaper::default();
+        assert_eq!(cell_width("a\u{6f22}b", &shaper.config), 4);
+        assert_eq!(
+            cell_width(&expand_tabs("a\tb", &shaper.config), &shaper.config),
+            9
+        );
+
+        let shaped_cells: usize = shaper
+            .shape("a\u{6f22}b", FontStyle::empty())
+            .iter()
+            .map(|run| run.cell_width)
+            .sum();
+        assert_eq!(shaped_cells, 4);
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    #[ignore = "needs the system default font installed, Consolas, Droid Sans Mono or Menlo"]
+    fn test_default_monospace_font_is_an_implicit_fallback() {
+        let mut shaper = CachingShaper::default();
+        shaper.update_font(&format!("{}:h14", EXTRA_SYMBOL_FONT));
+        let default_font_names: Vec<String> = shaper
+            .font_loader
+            .get_or_load(SYSTEM_DEFAULT_FONT)
+            .map(|family| {
+                family
+                    .fonts
+                    .iter()
+                    .map(|font| font.font.full_name())
+                    .collect()
+            })
+            .unwrap();
+
+        let chain = shaper.fallback_chain(FontStyle::empty());
+        assert!(default_font_names.contains(&chain[1]));
+        let latin_run = &shaper.layout_runs("a", false, false)[0];
+        assert!(!shaper.font_set.is_missing_glyph_font(&latin_run.font));
+
+        let config = ShaperConfig {
+            monospace_fallback: false,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        shaper.update_font(&format!("{}:h14", EXTRA_SYMBOL_FONT));
+        let chain = shaper.fallback_chain(FontStyle::empty());
+        assert!(chain
+            .iter()
+            .all(|font_name| !default_font_names.contains(font_name)));
+    }
+
+    #[test]
+    fn test_cached_runs_outlive_further_shaping() {
+        let mut shaper = CachingShaper::default();
+        let cells: Vec<Rc<Vec<ShapedRun>>> = "abcdefghij"
+            .chars()
+            .map(|character| shaper.shape_cached(&character.to_string(), FontStyle::empty()))
+            .collect();
+        shaper.shape_cached("klmnop", FontStyle::BOLD);
+
+        assert_eq!(cells.len(), 10);
+        assert!(cells.iter().all(|runs| runs.len() == 1));
+        let again = shaper.shape_cached("a", FontStyle::empty());
+        assert!(Rc::ptr_eq(&cells[0], &again));
+    }
+
+    #[test]
+    fn test_ambiguous_width_is_configurable() {
+        let alpha = "\u{3b1}";
+        let narrow_shaper = CachingShaper::default();
+        assert_eq!(cell_width(alpha, &narrow_shaper.config), 1);
+
+        let config = ShaperConfig {
+            ambiguous_width: AmbiguousWidth::Wide,
+            ..ShaperConfig::default()
+        };
+        let mut wide_shaper = CachingShaper::new(config, CacheSettings::default());
+        assert_eq!(cell_width(alpha, &wide_shaper.config), 2);
+        assert_eq!(cell_width("a", &wide_shaper.config), 1);
+
+        let shaped_cells: usize = wide_shaper
+            .shape(alpha, FontStyle::empty())
+            .iter()
+            .map(|run| run.cell_width)
+            .sum();
+        assert_eq!(shaped_cells, 2);
+    }
+
+    #[test]
+    fn test_nearest_bitmap_strike_is_used() {
+        let mut cblc = vec![0, 3, 0, 0, 0, 0, 0, 2];
+        for ppem in [20u8, 32].iter() {
+            let mut bitmap_size = vec![0; CBLC_BITMAP_SIZE_LENGTH];
+            bitmap_size[CBLC_PPEM_OFFSET] = *ppem;
+            bitmap_size[CBLC_PPEM_OFFSET + 1] = *ppem;
+            cblc.extend(bitmap_size);
+        }
+        assert_eq!(cblc_strike_sizes(&cblc), vec![20, 32]);
+        assert!(cblc_strike_sizes(&cblc[..40]).is_empty());
+
+        let sbix = [
+            0, 1, 0, 1, 0, 0, 0, 2, 0, 0, 0, 16, 0, 0, 0, 20, 0, 64, 0, 72, 0, 109, 0, 72,
+        ];
+        assert_eq!(sbix_strike_sizes(&sbix), vec![64, 109]);
+
+        let strikes = [20, 32, 64, 109];
+        assert_eq!(nearest_strike(&strikes, 30.0), Some(32));
+        assert_eq!(nearest_strike(&strikes, 100.0), Some(109));
+        assert_eq!(nearest_strike(&[], 14.0), None);
+        assert!((bitmap_font_size(&strikes, 30.0) - 32.0).abs() < std::f32::EPSILON);
+        assert!((bitmap_font_size(&strikes, 14.0) - 14.0).abs() < std::f32::EPSILON);
+        assert!((bitmap_font_size(&[], 14.0) - 14.0).abs() < std::f32::EPSILON);
+    }
+
+    #[test]
+    fn test_shaping_can_be_traced() {
+        log::set_logger(&LOGGER).ok();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut shaper = CachingShaper::default();
+        shaper.shape_cached("traced untraced", FontStyle::empty());
+        shaper.trace_shaping = true;
+        shaper.shape_cached("traced text", FontStyle::empty());
+
+        let logs = CAPTURED_LOGS.lock();
+        assert!(logs
+            .iter()
+            .any(|line| line.starts_with("Shaped \"traced text\" into")));
+        assert!(logs
+            .iter()
+            .any(|line| line.starts_with("Blob cache miss") && line.contains("traced text")));
+        assert!(!logs.iter().any(|line| line.contains("traced untraced")));
+    }
+
+    #[test]
+    fn test_wide_glyphs_are_clamped_to_their_cells() {
+        let font_width = 8.0;
+        let scale_x = clamped_scale_x(3.0 * font_width, 2, font_width);
+        assert!((3.0 * font_width * scale_x - 2.0 * font_width).abs() < 0.001);
+        assert!((clamped_scale_x(2.0 * font_width, 2, font_width) - 1.0).abs() < std::f32::EPSILON);
+        assert!((clamped_scale_x(font_width, 2, font_width) - 1.0).abs() < std::f32::EPSILON);
+
+        let runs = CachingShaper::default().layout_runs("abc", false, false);
+        assert!((runs[0].scale_x - 1.0).abs() < std::f32::EPSILON);
+    }
+
+    #[test]
+    fn test_missing_families_are_looked_up_again_when_asked_for() {
+        let mut shaper = CachingShaper::default();
+        shaper.update_font("Font Installed After Startup:h12");
+        shaper.update_font("Font Removed After Startup:h12");
+        assert!(shaper
+            .font_loader
+            .missing
+            .contains("Font Removed After Startup"));
+
+        // Asking for a missing family again starts over with the fonts installed now
+        shaper.update_font("Font Installed After Startup:h12");
+        assert!(!shaper
+            .font_loader
+            .missing
+            .contains("Font Removed After Startup"));
+        assert!(shaper
+            .font_loader
+            .missing
+            .contains("Font Installed After Startup"));
+    }
+
+    #[cfg(feature = "embed-fonts")]
+    #[test]
+    fn test_fallback_runs_share_the_primary_baseline() {
+        let mut shaper = CachingShaper::default();
+        let baseline = shaper.snapped_cell_metrics().baseline;
+        let runs = shaper.shape("a\u{10fffd}b", FontStyle::empty());
+
+        assert!(runs.len() > 1);
+        assert!(runs.iter().any(|run| run.used_fallback));
+        assert!(runs
+            .iter()
+            .all(|run| (run.baseline - baseline).abs() < std::f32::EPSILON));
+    }
+
+    #[test]
+    fn test_metrics_cache_round_trips() {
+        let mut shaper = CachingShaper::default();
+        let metrics_cache = shaper.metrics_cache();
+        assert!(!metrics_cache.font_name.is_empty());
+
+        let bytes = metrics_cache.serialize();
+        assert_eq!(MetricsCache::deserialize(&bytes), Some(metrics_cache));
+        assert_eq!(MetricsCache::deserialize(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(MetricsCache::deserialize(b"not a metrics cache"), None);
+    }
+
+    #[test]
+    fn test_metrics_cache_needs_the_same_font_and_spacing() {
+        let mut metrics_cache = CachingShaper::default().metrics_cache();
+        metrics_cache.cell_metrics = (3.0, 7.0);
+
+        let mut shaper = CachingShaper::default();
+        assert!(shaper.use_metrics_cache(&metrics_cache));
+        assert_eq!(shaper.cell_metrics(), (3.0, 7.0));
+
+        let spaced_config = |letter_spacing, line_spacing| ShaperConfig {
+            letter_spacing,
+            line_spacing,
+            ..ShaperConfig::default()
+        };
+        for config in [spaced_config(1.0, 0.0), spaced_config(0.0, 1.0)].iter() {
+            let mut spaced_shaper = CachingShaper::new(config.clone(), CacheSettings::default());
+            assert!(!spaced_shaper.use_metrics_cache(&metrics_cache));
+            assert_ne!(spaced_shaper.cell_metrics(), (3.0, 7.0));
+        }
+
+        let mut measured_shaper = CachingShaper::new(
+            ShaperConfig {
+                measurement_string: String::from("MMMM"),
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        assert!(!measured_shaper.use_metrics_cache(&metrics_cache));
+
+        let other_font = MetricsCache {
+            font_name: String::from("Some Other Font"),
+            ..metrics_cache
+        };
+        let mut shaper = CachingShaper::default();
+        assert!(!shaper.use_metrics_cache(&other_font));
+        assert_ne!(shaper.cell_metrics(), (3.0, 7.0));
+    }
+
+    #[test]
+    fn test_width_overrides_win() {
+        let mut width_overrides = HashMap::new();
+        width_overrides.insert('\u{6f22}', 1);
+        let config = ShaperConfig {
+            width_overrides,
+            ..ShaperConfig::default()
+        };
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+
+        assert_eq!(cell_width("a\u{6f22}b", &shaper.config), 3);
+        let runs = shaper.shape("\u{6f22}", FontStyle::empty());
+        assert_eq!(runs.iter().map(|run| run.cell_width).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn test_variation_selectors_stay_with_their_base() {
+        let shaper = CachingShaper::default();
+        for selector in ['\u{fe00}', '\u{e0100}'].iter() {
+            let text = format!("\u{845b}{}", selector);
+            let runs = shaper.layout_runs(&text, false, false);
+
+            assert_eq!(runs.len(), 1);
+            assert_eq!(runs[0].cell_width, 2);
+            assert!(!runs[0].missing.contains(selector));
+        }
+    }
+
+    #[test]
+    fn test_reverse_runs_are_cached_separately() {
+        let mut shaper = CachingShaper::default();
+        let runs = shaper.shape_cached("abc", FontStyle::empty());
+        let reversed_runs = shaper.shape_cached("abc", FontStyle::REVERSE);
+        shaper.shape_cached("abc", FontStyle::REVERSE | FontStyle::UNDERLINE);
+
+        assert!(runs.iter().all(|run| !run.reverse));
+        assert!(reversed_runs.iter().all(|run| run.reverse));
+        assert_eq!(shaper.blob_cache.entries.len(), 2);
+        assert_eq!(shaper.stats.blob_hits, 1);
+    }
+
+    #[test]
+    fn test_snapped_cell_metrics_fractional_height() {
+        let mut shaper = CachingShaper::new(
+            ShaperConfig {
+                line_spacing: 0.3,
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        let snapped = shaper.snapped_cell_metrics();
+        let (_, font_height) = shaper.cell_metrics();
+        assert!(snapped.snapped_row_height.fract().abs() < std::f32::EPSILON);
+        assert!(snapped.snapped_row_height >= font_height);
+        assert!(snapped.baseline.fract().abs() < std::f32::EPSILON);
+        assert!(snapped.baseline >= 0.0);
+        assert!(snapped.baseline <= snapped.snapped_row_height);
+        assert_eq!(
+            shaper.font_base_dimensions(),
+            (snapped.font_width, snapped.snapped_row_height)
+        );
+
+        // The glyphs of a run sit on the snapped baseline, so an x without descender ends on
+        // the row of pixels just above it
+        let runs = shaper.shape("x", FontStyle::empty());
+        assert!((runs[0].baseline - snapped.baseline).abs() < std::f32::EPSILON);
+        let (width, height) = (32, snapped.snapped_row_height as i32);
+        let mut surface = Surface::new_raster_n32_premul((width, height)).unwrap();
+        let paint = Paint::default();
+        surface.canvas().draw_text_blob(
+            runs[0].blob.as_ref().unwrap(),
+            (0.0, runs[0].baseline),
+            &paint,
+        );
+        let info = ImageInfo::new_n32_premul((width, height), None);
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        assert!(surface.read_pixels(&info, &mut pixels, (width * 4) as usize, (0, 0)));
+        let lowest_row = pixels
+            .chunks((width * 4) as usize)
+            .rposition(|row| row.chunks(4).any(|pixel| pixel[3] >= 128))
+            .unwrap();
+        assert_eq!(lowest_row as f32, snapped.baseline - 1.0);
+    }
+
+    #[test]
+    fn test_emoji_family_without_faces_is_skipped() {
+        let config = ShaperConfig {
+            emoji_family: Some(String::from("Emoji Family Without Faces")),
+            ..ShaperConfig::default()
+        };
+        let mut loader = FontLoader::new();
+        loader.cache.put(
+            String::from("Emoji Family Without Faces"),
+            ExtendedFontFamily::new(),
+        );
+        assert!(loader.get_or_load("Emoji Family Without Faces").is_none());
+
+        let font_set = FontSet::new(
+            &[String::from(SYSTEM_DEFAULT_FONT)],
+            &[],
+            &mut loader,
+            &config,
+        );
+        assert!(font_set.emoji_fonts.is_empty());
+        let (_, font) = font_set.normal.itemize("a").next().unwrap();
+        assert!(font_set.is_primary(font));
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with U+1F600, such as Noto Color Emoji"]
+    fn test_astral_characters_are_positioned_by_cluster() {
+        let mut shaper = CachingShaper::default();
+        assert!(shaper
+            .font_set
+            .emoji_fonts
+            .iter()
+            .any(|font| font_has_glyph(&font.font, '\u{1F600}')));
+        let (font_width, _) = shaper.font_base_dimensions();
+
+        let runs = shaper.layout_runs("\u{1F600}", false, false);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].glyphs.len(), 1);
+        assert!(runs[0].glyphs[0].position.x.abs() < std::f32::EPSILON);
+        assert_eq!(runs[0].cell_width, 2);
+        assert!((runs[0].advance - 2.0 * font_width).abs() < 0.01);
+
+        // Text after the emoji starts two cells later, not at its UTF-8 or UTF-16 length
+        let runs = shaper.layout_runs("a\u{1F600}b", false, false);
+        let last_glyph = runs.last().unwrap().glyphs.last().unwrap();
+        assert!((last_glyph.position.x - 3.0 * font_width).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_no_break_spaces_take_the_space_advance() {
+        let mut shaper = CachingShaper::default();
+        let space = shaper.shape("  ", FontStyle::empty());
+        let no_break_space = shaper.shape("\u{a0}\u{202f}", FontStyle::empty());
+        assert_eq!(no_break_space.len(), 1);
+        assert!(no_break_space[0].blob.is_none());
+        assert_eq!(no_break_space[0].cell_width, 2);
+        assert!((no_break_space[0].advance - space[0].advance).abs() < std::f32::EPSILON);
+
+        let spaced = shaper.layout_runs("a b", false, false);
+        let no_break_spaced = shaper.layout_runs("a\u{a0}b", false, false);
+        assert_eq!(no_break_spaced.len(), 1);
+        assert!(no_break_spaced[0].primary);
+        assert_eq!(no_break_spaced[0].glyphs, spaced[0].glyphs);
+        assert!((no_break_spaced[0].advance - spaced[0].advance).abs() < std::f32::EPSILON);
+    }
+
+    #[test]
+    fn test_control_characters_can_be_shown() {
+        let config = ShaperConfig {
+            show_control_chars: true,
+            ..ShaperConfig::default()
+        };
+        assert_eq!(
+            control_pictures("a\u{7}\u{7f}", &config),
+            "a\u{2407}\u{2421}"
+        );
+        assert!(matches!(control_pictures("a b", &config), Cow::Borrowed(_)));
+        assert!(matches!(
+            control_pictures("\u{7}", &ShaperConfig::default()),
+            Cow::Borrowed(_)
+        ));
+
+        let mut shaper = CachingShaper::new(config, CacheSettings::default());
+        let runs = shaper.layout_runs("\u{7}", false, false);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].glyphs.len(), 1);
+        assert_eq!(runs[0].cell_width, 1);
+
+        let shaped = shaper.shape("\u{7}", FontStyle::empty());
+        assert_eq!(shaped.iter().map(|run| run.cell_width).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn test_kerning_can_be_disabled() {
+        assert!(ShaperConfig::default().kerning(&[]));
+        let config = ShaperConfig {
+            kerning: false,
+            ..ShaperConfig::default()
+        };
+        assert!(!config.kerning(&[]));
+        assert!(config.kerning(&[(String::from("kern"), 1)]));
+    }
+
+    #[test]
+    #[ignore = "needs DejaVu Sans installed"]
+    fn test_kerning_is_turned_off_in_the_font() {
+        // DejaVu Sans kerns AV and has an fi ligature
+        let kerned_config = ShaperConfig {
+            kerning: true,
+            subpixel: true,
+            letter_spacing: 1.0,
+            clamp_wide_glyphs: false,
+            ..ShaperConfig::default()
+        };
+        let unkerned_config = ShaperConfig {
+            kerning: false,
+            ..kerned_config.clone()
+        };
+        let mut kerned = CachingShaper::new(kerned_config, CacheSettings::default());
+        let mut unkerned = CachingShaper::new(unkerned_config, CacheSettings::default());
+        kerned.update_font("DejaVu Sans:h14");
+        unkerned.update_font("DejaVu Sans:h14");
+        assert_eq!(
+            kerned.layout_runs("A", false, false)[0]
+                .font
+                .font
+                .family_name(),
+            "DejaVu Sans"
+        );
+
+        let advance = |shaper: &CachingShaper, text: &str| -> f32 {
+            shaper
+                .layout_runs(text, false, false)
+                .iter()
+                .map(|run| run.advance)
+                .sum()
+        };
+        let glyph_count = |shaper: &CachingShaper, text: &str| -> usize {
+            shaper
+                .layout_runs(text, false, false)
+                .iter()
+                .map(|run| run.glyphs.len())
+                .sum()
+        };
+        assert!(advance(&kerned, "AV") < advance(&kerned, "A") + advance(&kerned, "V") - 0.1);
+
+        // Without kerning a pair is exactly as wide as its glyphs on their own, letter spacing
+        // included, while ligatures still form
+        let unkerned_pair = advance(&unkerned, "A") + advance(&unkerned, "V");
+        assert!((advance(&unkerned, "AV") - unkerned_pair).abs() < 0.001);
+        assert_eq!(glyph_count(&kerned, "fi"), 1);
+        assert_eq!(glyph_count(&unkerned, "fi"), 1);
+    }
+
+    #[test]
+    fn test_runs_report_their_clip_width() {
+        let mut shaper = CachingShaper::default();
+        let (font_width, _) = shaper.font_base_dimensions();
+        let runs = shaper.shape("ab  ", FontStyle::empty());
+        for run in runs.iter() {
+            assert!((run.clip_width - run.cell_width as f32 * font_width).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a system emoji font with U+1F600, such as Noto Color Emoji"]
+    fn test_wide_runs_clip_to_both_cells() {
+        let mut shaper = CachingShaper::default();
+        let (font_width, _) = shaper.font_base_dimensions();
+        assert!(shaper
+            .font_set
+            .emoji_fonts
+            .iter()
+            .any(|font| font_has_glyph(&font.font, '\u{1F600}')));
+        let runs = shaper.shape("\u{1F600}", FontStyle::empty());
+        assert_eq!(runs.len(), 1);
+        assert!((runs[0].clip_width - 2.0 * font_width).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_bounded_chunks_prefer_spaces() {
+        assert_eq!(bounded_chunks("aa bb cc", 4), vec!["aa ", "bb ", "cc"]);
+        assert_eq!(bounded_chunks("abcdefg", 3), vec!["abc", "def", "g"]);
+        assert_eq!(
+            bounded_chunks("e\u{301}e\u{301}", 1),
+            vec!["e\u{301}", "e\u{301}"]
+        );
+        assert!(bounded_chunks("", 3).is_empty());
+    }
+
+    #[test]
+    fn test_long_lines_are_shaped_in_segments() {
+        let mut shaper = CachingShaper::default();
+        let line = "abcdefghij".repeat(1000);
+        let segments = shaper.shape_line_incremental(&[], &line, FontStyle::empty(), 0);
+
+        assert!(segments.len() > 1);
+        let max_shape_len = shaper.config.max_shape_len;
+        let mut cell_offset = 0;
+        for segment in segments.iter() {
+            assert!(segment.text.len() <= max_shape_len);
+            assert_eq!(segment.cell_offset, cell_offset);
+            assert!(!segment.runs.is_empty());
+            cell_offset += segment.runs.iter().map(|run| run.cell_width).sum::<usize>();
+        }
+        assert_eq!(cell_offset, 10000);
+        let text: String = segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect();
+        assert_eq!(text, line);
+    }
+
+    #[test]
+    fn test_incremental_line_shaping_reshapes_stale_chunks() {
+        let mut shaper = CachingShaper::default();
+        let line = "one two three";
+        let shaped = shaper.shape_line_incremental(&[], line, FontStyle::empty(), 0);
+        assert_eq!(shaper.chunks_shaped, 3);
+
+        let bold = shaper.shape_line_incremental(&shaped, line, FontStyle::BOLD, 0);
+        assert_eq!(shaper.chunks_shaped, 6);
+        assert!(bold.iter().all(|chunk| chunk.style == FontStyle::BOLD));
+
+        let blended = shaper.shape_line_incremental(&bold, line, FontStyle::BOLD, 30);
+        assert_eq!(shaper.chunks_shaped, 9);
+        assert!(blended
+            .iter()
+            .all(|chunk| chunk.runs.iter().all(|run| run.blend == 30)));
+
+        shaper.apply_guifont(&format!("{}:h20", SYSTEM_DEFAULT_FONT));
+        shaper.shape_line_incremental(&blended, line, FontStyle::BOLD, 30);
+        assert_eq!(shaper.chunks_shaped, 12);
+    }
+
+    #[test]
+    fn test_long_text_is_laid_out_in_bounded_pieces() {
+        assert_eq!(
+            bounded_segments(LayoutSegment::Text("abcdefg"), 3),
+            vec![
+                LayoutSegment::Text("abc"),
+                LayoutSegment::Text("def"),
+                LayoutSegment::Text("g")
+            ]
+        );
+        assert_eq!(
+            bounded_segments(LayoutSegment::Cluster("e\u{301}"), 1),
+            vec![LayoutSegment::Cluster("e\u{301}")]
+        );
+
+        let shaper = CachingShaper::default();
+        let bounded_shaper = CachingShaper::new(
+            ShaperConfig {
+                max_shape_len: 4,
+                ..ShaperConfig::default()
+            },
+            CacheSettings::default(),
+        );
+        let runs = shaper.layout_runs("abcdefghij", false, false);
+        let bounded_runs = bounded_shaper.layout_runs("abcdefghij", false, false);
+        assert_eq!(runs.len(), bounded_runs.len());
+        for (run, bounded_run) in runs.iter().zip(bounded_runs.iter()) {
+            assert_eq!(run.cell_width, bounded_run.cell_width);
+            for (glyph, bounded_glyph) in run.glyphs.iter().zip(bounded_run.glyphs.iter()) {
+                assert_eq!(glyph.glyph_id, bounded_glyph.glyph_id);
+                assert!((glyph.position.x - bounded_glyph.position.x).abs() < 0.001);
+            }
+        }
     }
 }
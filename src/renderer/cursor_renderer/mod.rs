@@ -6,7 +6,7 @@ use skulpin::skia_safe::{Canvas, Paint, Path, Point};
 
 use crate::editor::{Colors, Cursor, CursorShape, EDITOR};
 use crate::redraw_scheduler::REDRAW_SCHEDULER;
-use crate::renderer::CachingShaper;
+use crate::renderer::{CachingShaper, FontStyle};
 use crate::settings::*;
 
 use crate::bridge::EditorMode;
@@ -366,11 +366,16 @@ impl CursorRenderer {
 
             canvas.save();
             canvas.clip_path(&path, None, Some(false));
-
-            let blobs = &shaper.shape_cached(&character, false, false);
-
-            for blob in blobs.iter() {
-                canvas.draw_text_blob(&blob, destination, &paint);
+            // Runs are shaped in device pixels
+            let scale_factor = shaper.scale_factor();
+            canvas.translate(destination);
+            canvas.scale((1.0 / scale_factor, 1.0 / scale_factor));
+
+            if let Some(shaped_run) = shaper.shape_single_cell(&character, FontStyle::empty()) {
+                shaped_run.apply_paint_style(&mut paint);
+                if let Some(blob) = shaped_run.blob.as_ref() {
+                    canvas.draw_text_blob(blob, (0.0, shaped_run.baseline), &paint);
+                }
             }
 
             canvas.restore();
@@ -1,8 +1,74 @@
+use std::ops::RangeInclusive;
+
+use log::warn;
+
+const FEATURES_PREFIX: &str = "feat=";
+const FALLBACK_RULE_PREFIX: &str = "pin=";
+
+// Code points which are always taken from a family when it has glyphs for them
+#[derive(Clone, PartialEq, Debug)]
+pub struct FallbackRule {
+    pub range: RangeInclusive<u32>,
+    pub family: String,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct FontOptions {
     previous_guifont_setting: Option<String>,
     pub fallback_list: Vec<String>,
     pub size: f32,
+    // OpenType features from a :feat=calt=0,liga=0 option, on top of the configured ones
+    pub features: Vec<(String, u32)>,
+    // Ranges pinned to a family with :pin=4E00-9FFF=Family options
+    pub fallback_rules: Vec<FallbackRule>,
+}
+
+// Features are given as tag=value pairs, a tag alone turns the feature on
+fn parse_features(features_setting: &str) -> Vec<(String, u32)> {
+    features_setting
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .filter_map(|feature| {
+            let mut parts = feature.splitn(2, '=');
+            let tag = parts.next()?;
+            let value = parts.next().map(|value| value.parse::<u32>().ok());
+            let valid_tag = tag.len() == 4 && tag.chars().all(|c| c.is_ascii_alphanumeric());
+            match value {
+                Some(Some(value)) if valid_tag => Some((tag.to_string(), value)),
+                None if valid_tag => Some((tag.to_string(), 1)),
+                _ => {
+                    warn!("Ignoring font feature {} in guifont", feature);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Ranges are given in hex, a single code point stands for a range of its own
+fn parse_fallback_rule(rule_setting: &str) -> Option<FallbackRule> {
+    let mut parts = rule_setting.splitn(2, '=');
+    let range = parts.next()?;
+    let family = parts.next().filter(|family| !family.is_empty());
+    let mut bounds = range.splitn(2, '-');
+    let start = bounds
+        .next()
+        .and_then(|start| u32::from_str_radix(start, 16).ok());
+    let end = match bounds.next() {
+        Some(end) => u32::from_str_radix(end, 16).ok(),
+        None => start,
+    };
+
+    match (start, end, family) {
+        (Some(start), Some(end), Some(family)) if start <= end => Some(FallbackRule {
+            range: start..=end,
+            family: family.replace("\\ ", " "),
+        }),
+        _ => {
+            warn!("Ignoring fallback rule {} in guifont", rule_setting);
+            None
+        }
+    }
 }
 
 impl FontOptions {
@@ -11,6 +77,8 @@ impl FontOptions {
             previous_guifont_setting: None,
             fallback_list: vec![name],
             size,
+            features: Vec::new(),
+            fallback_rules: Vec::new(),
         }
     }
 
@@ -22,14 +90,26 @@ impl FontOptions {
         }
         self.previous_guifont_setting = Some(guifont_setting.to_string());
 
-        let mut parts = guifont_setting.split(':').filter(|part| !part.is_empty());
+        // Font files are given as file:// urls, so their colon doesn't start a new option
+        let mut joined_parts: Vec<String> = Vec::new();
+        for part in guifont_setting.split(':') {
+            match joined_parts.last_mut() {
+                Some(previous) if previous.ends_with("file") && part.starts_with("//") => {
+                    previous.push(':');
+                    previous.push_str(part);
+                }
+                _ => joined_parts.push(part.to_string()),
+            }
+        }
+        let mut parts = joined_parts.iter().filter(|part| !part.is_empty());
         let mut updated = false;
 
         if let Some(parts) = parts.next() {
+            // Spaces in family names may be escaped like in vim's own guifont setting
             let parsed_fallback_list: Vec<String> = parts
                 .split(',')
                 .filter(|fallback| !fallback.is_empty())
-                .map(|fallback| fallback.to_string())
+                .map(|fallback| fallback.replace("\\ ", " "))
                 .collect();
 
             if !parsed_fallback_list.is_empty() && self.fallback_list != parsed_fallback_list {
@@ -38,8 +118,15 @@ impl FontOptions {
             }
         }
 
+        let mut parsed_features = Vec::new();
+        let mut parsed_fallback_rules = Vec::new();
         for part in parts {
-            if part.starts_with('h') && part.len() > 1 {
+            if part.starts_with(FEATURES_PREFIX) {
+                parsed_features.extend(parse_features(&part[FEATURES_PREFIX.len()..]));
+            } else if part.starts_with(FALLBACK_RULE_PREFIX) {
+                parsed_fallback_rules
+                    .extend(parse_fallback_rule(&part[FALLBACK_RULE_PREFIX.len()..]));
+            } else if part.starts_with('h') && part.len() > 1 {
                 if let Ok(size) = part[1..].parse::<f32>() {
                     if (self.size - size).abs() > std::f32::EPSILON {
                         self.size = size;
@@ -49,6 +136,16 @@ impl FontOptions {
             }
         }
 
+        if self.features != parsed_features {
+            self.features = parsed_features;
+            updated = true;
+        }
+
+        if self.fallback_rules != parsed_fallback_rules {
+            self.fallback_rules = parsed_fallback_rules;
+            updated = true;
+        }
+
         updated
     }
 }
@@ -1,24 +1,178 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use log::trace;
 use skulpin::skia_safe::gpu::SurfaceOrigin;
-use skulpin::skia_safe::{colors, dash_path_effect, Budgeted, Canvas, Paint, Rect, Surface};
+use skulpin::skia_safe::{colors, Budgeted, Canvas, Color, Paint, PaintStyle, Path, Rect, Surface};
 use skulpin::CoordinateSystemHelper;
 
 mod caching_shaper;
 pub mod cursor_renderer;
 pub mod font_options;
 
-pub use caching_shaper::CachingShaper;
+pub use caching_shaper::{CachingShaper, FontStyle, TextShaper};
 pub use font_options::*;
 
 use crate::editor::{Style, EDITOR};
+use caching_shaper::LineChunk;
 use cursor_renderer::CursorRenderer;
 
+// The highlight's colors before reverse swaps them, which is left to each run. Decorations are
+// drawn in the special color
+struct TextColors {
+    foreground: Color,
+    background: Color,
+    special: Color,
+}
+
+// A wave of quadratic curves along the line, one up and one down per wavelength
+fn undercurl_path(x: f32, y: f32, width: f32, wavelength: f32, amplitude: f32) -> Path {
+    let half_wavelength = (wavelength / 2.0).max(1.0);
+    let mut path = Path::new();
+    path.move_to((x, y));
+
+    let mut start = x;
+    let mut direction = -1.0;
+    while start < x + width {
+        path.quad_to(
+            (start + half_wavelength / 2.0, y + direction * amplitude),
+            (start + half_wavelength, y),
+        );
+        start += half_wavelength;
+        direction = -direction;
+    }
+    path
+}
+
+fn set_stroke(paint: &mut Paint, color: Color, alpha: u8, stroke_width: f32) {
+    paint.set_color(color);
+    paint.set_alpha(alpha);
+    paint.set_style(PaintStyle::Stroke);
+    paint.set_stroke_width(stroke_width);
+}
+
+// Trailing whitespace is shaped with the text, so its cells still get decorated. Runs are faded by
+// the blend of their highlight, and decorations follow the metrics of the font each run came from
+// so they line up with its glyphs. Underlines go below the glyphs and strikethroughs above them.
+// The chunks the text was shaped in are handed back, so redrawing it after an edit only has to
+// shape the chunks which changed
+#[allow(clippy::too_many_arguments)]
+fn draw_text(
+    shaper: &mut dyn TextShaper,
+    canvas: &mut Canvas,
+    paint: &mut Paint,
+    previous: &[LineChunk],
+    text: &str,
+    style: FontStyle,
+    blend: u8,
+    colors: &TextColors,
+    position: (f32, f32),
+) -> Vec<LineChunk> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+
+    // Whatever is drawn with the paint afterwards shouldn't be faded by the last run's blend
+    let alpha = paint.alpha();
+    // Runs are shaped in device pixels and positioned relative to the start of the text
+    let scale_factor = shaper.scale_factor();
+    canvas.save();
+    canvas.translate(position);
+    canvas.scale((1.0 / scale_factor, 1.0 / scale_factor));
+
+    let (font_width, _) = shaper.cell_metrics();
+    let chunks = shaper.shape_line(previous, text, style, blend);
+    for chunk in chunks.iter() {
+        // Chunks are drawn from the cell they start at
+        canvas.save();
+        canvas.translate((chunk.cell_offset as f32 * font_width, 0.0));
+        let mut run_x = 0.0;
+        for shaped_run in chunk.runs.iter() {
+            let decorations = &shaped_run.decorations;
+            let run_end = run_x + shaped_run.clip_width;
+
+            let special_alpha = shaped_run.blended_alpha(colors.special.a());
+            if style.contains(FontStyle::UNDERCURL) {
+                set_stroke(
+                    paint,
+                    colors.special,
+                    special_alpha,
+                    decorations.underline_thickness,
+                );
+                canvas.draw_path(
+                    &undercurl_path(
+                        run_x,
+                        decorations.underline_y,
+                        shaped_run.clip_width,
+                        decorations.undercurl_wavelength,
+                        decorations.underline_thickness,
+                    ),
+                    paint,
+                );
+            } else if style.contains(FontStyle::UNDERLINE) {
+                set_stroke(
+                    paint,
+                    colors.special,
+                    special_alpha,
+                    decorations.underline_thickness,
+                );
+                let underline_y = decorations.underline_y;
+                canvas.draw_line((run_x, underline_y), (run_end, underline_y), paint);
+            }
+
+            let text_color = if shaped_run.reverse {
+                colors.background
+            } else {
+                colors.foreground
+            };
+            paint.set_color(text_color);
+            paint.set_alpha(shaped_run.blended_alpha(text_color.a()));
+            shaped_run.apply_paint_style(paint);
+            if let Some(blob) = shaped_run.blob.as_ref() {
+                canvas.draw_text_blob(blob, (0.0, shaped_run.baseline), paint);
+            }
+
+            if !shaped_run.codepoint_boxes.is_empty() {
+                paint.set_style(PaintStyle::Stroke);
+                paint.set_stroke_width(1.0);
+                for codepoint_box in shaped_run.codepoint_boxes.iter() {
+                    canvas.draw_rect(codepoint_box, paint);
+                }
+            }
+
+            if style.contains(FontStyle::STRIKETHROUGH) {
+                set_stroke(
+                    paint,
+                    colors.special,
+                    special_alpha,
+                    decorations.underline_thickness,
+                );
+                let strikethrough_y = decorations.strikethrough_y;
+                canvas.draw_line((run_x, strikethrough_y), (run_end, strikethrough_y), paint);
+            }
+
+            run_x += shaped_run.advance;
+        }
+        canvas.restore();
+    }
+    canvas.restore();
+    paint.set_style(PaintStyle::Fill);
+    paint.set_alpha(alpha);
+    chunks
+}
+
+// The cell size of the font is kept in the user's cache directory between sessions
+fn metrics_cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|cache_dir| cache_dir.join("neovide").join("font_metrics"))
+}
+
 pub struct Renderer {
     surface: Option<Surface>,
     paint: Paint,
     shaper: CachingShaper,
+    // The chunks each fragment of text was last shaped in, by the cell it starts at
+    line_chunks: HashMap<(u64, u64), Vec<LineChunk>>,
 
     pub font_width: f32,
     pub font_height: f32,
@@ -31,8 +185,14 @@ impl Renderer {
         let mut paint = Paint::new(colors::WHITE, None);
         paint.set_anti_alias(false);
 
-        let mut shaper = CachingShaper::new();
+        let mut shaper = CachingShaper::default();
+        if let Some(path) = metrics_cache_path() {
+            shaper.load_metrics_cache(&path);
+        }
+        shaper.preload();
 
+        // The grid is laid out in logical pixels, the scale factor of the window is picked up
+        // with the first frame
         let (font_width, font_height) = shaper.font_base_dimensions();
         let cursor_renderer = CursorRenderer::new();
 
@@ -40,20 +200,47 @@ impl Renderer {
             surface,
             paint,
             shaper,
+            line_chunks: HashMap::new(),
             font_width,
             font_height,
             cursor_renderer,
         }
     }
 
+    // Saves the cell size of the current font for the next session to start with
+    pub fn save_metrics_cache(&mut self) {
+        if let Some(path) = metrics_cache_path() {
+            self.shaper.save_metrics_cache(&path);
+        }
+    }
+
     fn update_font(&mut self, guifont_setting: &str) -> bool {
-        let updated = self.shaper.update_font(guifont_setting);
-        if updated {
-            let (font_width, font_height) = self.shaper.font_base_dimensions();
-            self.font_width = font_width;
-            self.font_height = font_height.ceil();
+        match self.shaper.apply_guifont(guifont_setting) {
+            Some((font_width, font_height)) => {
+                self.font_width = font_width;
+                self.font_height = font_height;
+                true
+            }
+            None => false,
         }
-        updated
+    }
+
+    // Moving to a monitor with another scale factor reshapes all text for its device pixels, so
+    // the whole grid is drawn again. Returns whether the scale factor changed, in which case the
+    // cell size may have changed with it
+    pub fn update_scale_factor(&mut self, scale_factor: f32) -> bool {
+        if (self.shaper.scale_factor() - scale_factor).abs() < std::f32::EPSILON {
+            return false;
+        }
+
+        let (font_width, font_height) = self.shaper.on_scale_change(scale_factor);
+        self.font_width = font_width;
+        self.font_height = font_height;
+
+        let mut editor = EDITOR.lock();
+        editor.grid.set_dirty_all(true);
+        editor.grid.should_clear = true;
+        true
     }
 
     fn compute_text_region(&self, grid_pos: (u64, u64), cell_width: u64) -> Rect {
@@ -93,9 +280,24 @@ impl Renderer {
         let (grid_x, grid_y) = grid_pos;
         let x = grid_x as f32 * self.font_width;
         let y = grid_y as f32 * self.font_height;
-        let width = cell_width as f32 * self.font_width;
 
         let style = style.as_ref().unwrap_or(default_style);
+        let (foreground, background) = if style.reverse {
+            (
+                style.background(&default_style.colors),
+                style.foreground(&default_style.colors),
+            )
+        } else {
+            (
+                style.foreground(&default_style.colors),
+                style.background(&default_style.colors),
+            )
+        };
+        let colors = TextColors {
+            foreground: foreground.to_color(),
+            background: background.to_color(),
+            special: style.special(&default_style.colors).to_color(),
+        };
 
         canvas.save();
 
@@ -103,48 +305,19 @@ impl Renderer {
 
         canvas.clip_rect(region, None, Some(false));
 
-        if style.underline || style.undercurl {
-            let line_position = self.shaper.underline_position();
-            let stroke_width = self.shaper.options.size / 10.0;
-            self.paint
-                .set_color(style.special(&default_style.colors).to_color());
-            self.paint.set_stroke_width(stroke_width);
-
-            if style.undercurl {
-                self.paint.set_path_effect(dash_path_effect::new(
-                    &[stroke_width * 2.0, stroke_width * 2.0],
-                    0.0,
-                ));
-            } else {
-                self.paint.set_path_effect(None);
-            }
-
-            canvas.draw_line(
-                (x, y - line_position + self.font_height),
-                (x + width, y - line_position + self.font_height),
-                &self.paint,
-            );
-        }
-
-        self.paint
-            .set_color(style.foreground(&default_style.colors).to_color());
-        let text = text.trim_end();
-        if !text.is_empty() {
-            for blob in self
-                .shaper
-                .shape_cached(text, style.bold, style.italic)
-                .iter()
-            {
-                canvas.draw_text_blob(blob, (x, y), &self.paint);
-            }
-        }
-
-        if style.strikethrough {
-            let line_position = region.center_y();
-            self.paint
-                .set_color(style.special(&default_style.colors).to_color());
-            canvas.draw_line((x, line_position), (x + width, line_position), &self.paint);
-        }
+        let previous = self.line_chunks.remove(&grid_pos).unwrap_or_default();
+        let chunks = draw_text(
+            &mut self.shaper,
+            canvas,
+            &mut self.paint,
+            &previous,
+            text,
+            FontStyle::from_highlight(style),
+            style.blend,
+            &colors,
+            (x, y),
+        );
+        self.line_chunks.insert(grid_pos, chunks);
 
         canvas.restore();
     }
@@ -173,6 +346,7 @@ impl Renderer {
 
         if should_clear {
             self.surface = None;
+            self.line_chunks.clear();
         }
 
         let mut surface = self.surface.take().unwrap_or_else(|| {
@@ -239,7 +413,269 @@ impl Renderer {
             gpu_canvas,
             dt,
         );
+        self.shaper.trace_stats();
 
         font_changed
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use caching_shaper::{DecorationMetrics, ShapedRun};
+    use skulpin::skia_safe::{AlphaType, ColorType, ImageInfo};
+
+    const TEST_COLORS: TextColors = TextColors {
+        foreground: Color::WHITE,
+        background: Color::BLUE,
+        special: Color::RED,
+    };
+
+    #[derive(Default)]
+    struct MockShaper {
+        shaped: Vec<(String, FontStyle, u8)>,
+        reused_chunks: usize,
+        runs: Vec<ShapedRun>,
+    }
+
+    impl TextShaper for MockShaper {
+        // Every run goes in a chunk of its own, one after the other
+        fn shape_line(
+            &mut self,
+            previous: &[LineChunk],
+            text: &str,
+            style: FontStyle,
+            blend: u8,
+        ) -> Vec<LineChunk> {
+            self.shaped.push((text.to_string(), style, blend));
+            self.reused_chunks += previous.len();
+            let mut cell_offset = 0;
+            self.runs
+                .iter()
+                .map(|run| {
+                    let chunk = LineChunk {
+                        text: text.to_string(),
+                        style,
+                        blend,
+                        font_generation: 0,
+                        cell_offset,
+                        runs: vec![run.clone()],
+                    };
+                    cell_offset += run.cell_width;
+                    chunk
+                })
+                .collect()
+        }
+
+        fn cell_metrics(&mut self) -> (f32, f32) {
+            (10.0, 20.0)
+        }
+
+        fn scale_factor(&self) -> f32 {
+            1.0
+        }
+    }
+
+    // Two cells without glyphs, so only decorations draw anything
+    fn decorated_run() -> ShapedRun {
+        ShapedRun {
+            font_name: String::from("Test Font"),
+            blob: None,
+            advance: 20.0,
+            cell_width: 2,
+            clip_width: 20.0,
+            used_fallback: false,
+            has_missing_glyphs: false,
+            glyph_count: 0,
+            decorations: DecorationMetrics {
+                underline_y: 16.0,
+                underline_thickness: 2.0,
+                undercurl_wavelength: 8.0,
+                strikethrough_y: 8.0,
+            },
+            synthetic_bold_stroke: None,
+            blend: 0,
+            codepoint_boxes: Vec::new(),
+            is_color: false,
+            reverse: false,
+            baseline: 14.0,
+        }
+    }
+
+    // Rows of a 40 by 20 surface with anything drawn in them
+    fn covered_rows(style: FontStyle) -> Vec<usize> {
+        let mut shaper = MockShaper {
+            runs: vec![decorated_run()],
+            ..MockShaper::default()
+        };
+        let (width, height) = (40, 20);
+        let mut surface = Surface::new_raster_n32_premul((width, height)).unwrap();
+        surface.canvas().clear(Color::TRANSPARENT);
+        draw_text(
+            &mut shaper,
+            surface.canvas(),
+            &mut Paint::default(),
+            &[],
+            "ab",
+            style,
+            0,
+            &TEST_COLORS,
+            (0.0, 0.0),
+        );
+
+        let info = ImageInfo::new_n32_premul((width, height), None);
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        assert!(surface.read_pixels(&info, &mut pixels, (width * 4) as usize, (0, 0)));
+        pixels
+            .chunks((width * 4) as usize)
+            .enumerate()
+            .filter(|(_, row)| row.chunks(4).any(|pixel| pixel[3] != 0))
+            .map(|(row, _)| row)
+            .collect()
+    }
+
+    #[test]
+    fn test_draw_text_keeps_trailing_whitespace() {
+        let mut shaper = MockShaper::default();
+        let mut surface = Surface::new_raster_n32_premul((16, 16)).unwrap();
+        let mut paint = Paint::default();
+
+        draw_text(
+            &mut shaper,
+            surface.canvas(),
+            &mut paint,
+            &[],
+            "abc  ",
+            FontStyle::BOLD,
+            30,
+            &TEST_COLORS,
+            (0.0, 0.0),
+        );
+        draw_text(
+            &mut shaper,
+            surface.canvas(),
+            &mut paint,
+            &[],
+            "",
+            FontStyle::empty(),
+            0,
+            &TEST_COLORS,
+            (0.0, 0.0),
+        );
+
+        assert_eq!(
+            shaper.shaped,
+            vec![(String::from("abc  "), FontStyle::BOLD, 30)]
+        );
+        assert_eq!(paint.style(), PaintStyle::Fill);
+        assert_eq!(paint.alpha(), 255);
+    }
+
+    #[test]
+    fn test_decorations_follow_the_run_metrics() {
+        assert!(covered_rows(FontStyle::empty()).is_empty());
+
+        let underline = covered_rows(FontStyle::UNDERLINE);
+        assert!(underline.contains(&16));
+        assert!(underline.iter().all(|row| (15..=17).contains(row)));
+
+        let strikethrough = covered_rows(FontStyle::STRIKETHROUGH);
+        assert!(strikethrough.contains(&8));
+        assert!(strikethrough.iter().all(|row| (7..=9).contains(row)));
+
+        // The curl swings a thickness above and below the underline
+        let undercurl = covered_rows(FontStyle::UNDERCURL);
+        assert!(undercurl.len() > underline.len());
+        assert!(undercurl.iter().all(|row| (13..=19).contains(row)));
+    }
+
+    #[test]
+    fn test_chunks_are_drawn_at_their_cells() {
+        let mut shaper = MockShaper {
+            runs: vec![decorated_run(), decorated_run()],
+            ..MockShaper::default()
+        };
+        let (width, height) = (60, 20);
+        let mut surface = Surface::new_raster_n32_premul((width, height)).unwrap();
+        surface.canvas().clear(Color::TRANSPARENT);
+        let chunks = draw_text(
+            &mut shaper,
+            surface.canvas(),
+            &mut Paint::default(),
+            &[],
+            "abcd",
+            FontStyle::UNDERLINE,
+            0,
+            &TEST_COLORS,
+            (0.0, 0.0),
+        );
+
+        // The second chunk starts two cells of 10 pixels in, right where the first one ends
+        let info = ImageInfo::new_n32_premul((width, height), None);
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+        assert!(surface.read_pixels(&info, &mut pixels, (width * 4) as usize, (0, 0)));
+        let underline_row = &pixels[(16 * width * 4) as usize..(17 * width * 4) as usize];
+        let covered = |column: usize| underline_row[column * 4 + 3] != 0;
+        assert!(covered(5) && covered(25) && covered(35));
+        assert!(!covered(45));
+
+        draw_text(
+            &mut shaper,
+            surface.canvas(),
+            &mut Paint::default(),
+            &chunks,
+            "abcd",
+            FontStyle::UNDERLINE,
+            0,
+            &TEST_COLORS,
+            (0.0, 0.0),
+        );
+        assert_eq!(shaper.reused_chunks, 2);
+    }
+
+    #[test]
+    fn test_reversed_runs_are_drawn_in_the_background_color() {
+        let drawn_colors = |reverse: bool| {
+            let mut run = decorated_run();
+            run.reverse = reverse;
+            run.codepoint_boxes = vec![Rect::new(2.0, 2.0, 12.0, 12.0)];
+            let mut shaper = MockShaper {
+                runs: vec![run],
+                ..MockShaper::default()
+            };
+            let (width, height) = (20, 20);
+            let mut surface = Surface::new_raster_n32_premul((width, height)).unwrap();
+            surface.canvas().clear(Color::TRANSPARENT);
+            draw_text(
+                &mut shaper,
+                surface.canvas(),
+                &mut Paint::default(),
+                &[],
+                "ab",
+                FontStyle::empty(),
+                0,
+                &TEST_COLORS,
+                (0.0, 0.0),
+            );
+
+            let info = ImageInfo::new(
+                (width, height),
+                ColorType::RGBA8888,
+                AlphaType::Premul,
+                None,
+            );
+            let mut pixels = vec![0u8; (width * height * 4) as usize];
+            assert!(surface.read_pixels(&info, &mut pixels, (width * 4) as usize, (0, 0)));
+            let mut colors: Vec<Vec<u8>> = pixels
+                .chunks(4)
+                .filter(|pixel| pixel[3] != 0)
+                .map(|pixel| pixel.to_vec())
+                .collect();
+            colors.dedup();
+            colors
+        };
+
+        assert_eq!(drawn_colors(false), vec![vec![255, 255, 255, 255]]);
+        assert_eq!(drawn_colors(true), vec![vec![0, 0, 255, 255]]);
+    }
+}
@@ -353,6 +353,11 @@ impl WindowWrapper {
 
         let sdl_window_wrapper = Sdl2Window::new(&self.window);
         let new_size = sdl_window_wrapper.logical_size();
+        let scale_factor = sdl_window_wrapper.scale_factor() as f32;
+        if self.renderer.update_scale_factor(scale_factor) {
+            handle_new_grid_size(new_size, &self.renderer);
+            REDRAW_SCHEDULER.queue_next_frame();
+        }
         if self.previous_size != new_size {
             handle_new_grid_size(new_size, &self.renderer);
             self.previous_size = new_size;
@@ -478,5 +483,6 @@ pub fn ui_loop() {
         }
     }
 
+    window.renderer.save_metrics_cache();
     std::process::exit(0);
 }